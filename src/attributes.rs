@@ -0,0 +1,465 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Memory Attributes
+//!
+//! The set of memory attributes a memory region can be mapped with. The attributes are the upper and lower attributes
+//! of the block and page entries of the translation tables. The memory type is selected as an index into the MAIR_ELx
+//! register which is configured as follows:
+//!
+//! |Index| Memory Type                                     |
+//! |-----|-------------------------------------------------|
+//! | 0   | Device-nGnRnE                                   |
+//! | 1   | Device-nGnRE                                    |
+//! | 2   | Device-GRE                                      |
+//! | 3   | Normal, non-cacheable                           |
+//! | 4   | Normal, write-back cacheable                    |
+//! | 5   | Device-nGRE                                     |
+//! | 6   | Normal, write-back cacheable, write-allocate    |
+//! | 7   | Normal, write-back cacheable, read-allocate     |
+//! |     | or Normal, tagged if MTE is supported           |
+//!
+//! The normal memory types are the same for the inner and the outer cacheability. Slot 4 allocates on read and write
+//! (MAIR byte ``0xFF``), slot 6 only allocates on write (``0xDD``) and slot 7 only allocates on read (``0xEE``). On a
+//! SoC supporting the memory tagging extension (see ``MmuFeatures::mte``) slot 7 is configured as tagged normal memory
+//! (``0xF0``) instead.
+//!
+//! The access permissions are given by the ``TTLB_BLOCKPAGE::AP`` field. The encodings ``RW_EL1_EL0`` and
+//! ``RO_EL1_EL0`` make the memory accessible from EL0. With privileged-access-never (PAN) enabled (see ``set_pan``)
+//! any data access from EL1 to such memory faults, while the EL1 only encodings ``RW_EL1`` and ``RO_EL1`` are not
+//! affected by PAN. All constructors provided here use ``RW_EL1``.
+//!
+
+use ruspiro_arch_aarch64::RegisterFieldValue;
+
+use super::{config::TTLB_BLOCKPAGE, features::features};
+
+/// The ordering constraints of device memory, selecting the corresponding MAIR slot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceOrdering {
+  /// No gathering, no re-ordering and no early write acknowledgement (Device-nGnRnE) for strictly ordered registers
+  NGnRnE,
+  /// No gathering, no re-ordering but early write acknowledgement (Device-nGnRE)
+  NGnRE,
+  /// No gathering but re-ordering and early write acknowledgement (Device-nGRE)
+  NGRE,
+  /// Gathering, re-ordering and early write acknowledgement (Device-GRE) for relaxed ordered data like a framebuffer
+  GRE,
+}
+
+impl DeviceOrdering {
+  /// The MAIR slot configured with the memory type of this ordering
+  fn mair(self) -> RegisterFieldValue<u64> {
+    match self {
+      DeviceOrdering::NGnRnE => TTLB_BLOCKPAGE::MEMATTR::MAIR0,
+      DeviceOrdering::NGnRE => TTLB_BLOCKPAGE::MEMATTR::MAIR1,
+      DeviceOrdering::NGRE => TTLB_BLOCKPAGE::MEMATTR::MAIR5,
+      DeviceOrdering::GRE => TTLB_BLOCKPAGE::MEMATTR::MAIR2,
+    }
+  }
+}
+
+/// The MAIR byte of the Device-nGRE memory type slot 5 is configured with
+pub(crate) const MAIR_DEVICE_NGRE: u64 = 0b0000_1000;
+
+/// The MAIR byte of normal write-back memory that only allocates on write, slot 6 is configured with
+pub(crate) const MAIR_NORMAL_WB_NO_READ_ALLOC: u64 = 0b1101_1101;
+/// The MAIR byte of normal write-back memory that only allocates on read
+pub(crate) const MAIR_NORMAL_WB_NO_WRITE_ALLOC: u64 = 0b1110_1110;
+/// The MAIR byte of tagged normal memory
+pub(crate) const MAIR_NORMAL_TAGGED: u64 = 0b1111_0000;
+
+/// The MAIR byte the slot 7 is configured with, tagged normal memory if the SoC supports the memory tagging extension
+pub(crate) fn mair7_normal() -> u64 {
+  if features().mte {
+    MAIR_NORMAL_TAGGED
+  } else {
+    MAIR_NORMAL_WB_NO_WRITE_ALLOC
+  }
+}
+
+/// The memory attributes of a block or page entry. The entry type, contiguous hint and output address bits are never
+/// part of the attributes, so two attributes compare equal if all attribute bits match regardless of the address they
+/// were taken from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MemoryAttributes(u64);
+
+/// The attribute fields that differ between two ``MemoryAttributes``
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AttrDiff {
+  /// The memory type, the index of the MAIR_ELx slot, differs
+  pub mair: bool,
+  /// The access permissions differ
+  pub ap: bool,
+  /// The shareability differs
+  pub sh: bool,
+  /// The unprivileged or privileged execute never bit differs
+  pub xn: bool,
+}
+
+impl AttrDiff {
+  /// Whether any of the memory type, access permissions, shareability or execute never bits differ. Other attribute
+  /// bits like the access flag or the software bits are not covered.
+  pub fn any(&self) -> bool {
+    self.mair || self.ap || self.sh || self.xn
+  }
+}
+
+impl MemoryAttributes {
+  /// Create the memory attributes from the raw bits of a block or page entry. The entry type, contiguous hint and
+  /// output address bits are ignored.
+  pub fn from_raw(raw: u64) -> Self {
+    Self(
+      raw
+        & !(TTLB_BLOCKPAGE::TYPE::from_raw(!0)
+          | TTLB_BLOCKPAGE::ADDR::from_raw(!0)
+          | TTLB_BLOCKPAGE::C::from_raw(!0))
+        .raw_value(),
+    )
+  }
+
+  /// The raw bits of the memory attributes as they are stored in a block or page entry
+  pub fn raw(self) -> u64 {
+    self.0
+  }
+
+  /// Store the given value in the 4 bits of the entry reserved for software use. The bits are ignored by the MMU and
+  /// allow to keep some metadata of the mapped memory directly in the translation table entry. Only the lower 4 bits
+  /// of the value are stored.
+  pub fn with_sw_bits(self, bits: u8) -> Self {
+    Self(
+      (self.0 & !TTLB_BLOCKPAGE::SW::from_raw(!0).raw_value())
+        | TTLB_BLOCKPAGE::SW::with_value(bits as u64 & 0xF).raw_value(),
+    )
+  }
+
+  /// The value stored in the 4 bits of the entry reserved for software use
+  pub fn sw_bits(self) -> u8 {
+    TTLB_BLOCKPAGE::SW::from_raw(self.0).value() as u8
+  }
+
+  /// Determine which of the memory type, access permissions, shareability and execute never fields of these attributes
+  /// differ from the ``other`` attributes
+  pub fn differs_from(&self, other: &MemoryAttributes) -> AttrDiff {
+    let differs = |mask: u64| self.0 & mask != other.0 & mask;
+    AttrDiff {
+      mair: differs(TTLB_BLOCKPAGE::MEMATTR::from_raw(!0).raw_value()),
+      ap: differs(TTLB_BLOCKPAGE::AP::from_raw(!0).raw_value()),
+      sh: differs(TTLB_BLOCKPAGE::SH::from_raw(!0).raw_value()),
+      xn: differs(
+        (TTLB_BLOCKPAGE::XN::from_raw(!0) | TTLB_BLOCKPAGE::PXN::from_raw(!0)).raw_value(),
+      ),
+    }
+  }
+
+  /// The index of the MAIR_ELx register slot the memory type of these attributes resolves to. See the module
+  /// documentation for the memory type each slot is configured with.
+  pub fn mair_index(&self) -> u8 {
+    TTLB_BLOCKPAGE::MEMATTR::from_raw(self.0).value() as u8
+  }
+
+  /// Whether the attributes match the inner shareable and write-back cacheable memory the translation table walk is
+  /// configured to access the translation tables with
+  pub(crate) fn is_table_walk_compatible(self) -> bool {
+    TTLB_BLOCKPAGE::MEMATTR::from_raw(self.0).value() == TTLB_BLOCKPAGE::MEMATTR::MAIR4.value()
+      && TTLB_BLOCKPAGE::SH::from_raw(self.0).value() == TTLB_BLOCKPAGE::SH::INNER.value()
+  }
+
+  /// Normal memory that is inner shareable and write-back cacheable. This is how the RAM is mapped in the initial
+  /// 1:1 mapping.
+  pub fn normal_cached() -> Self {
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR4)
+      .into()
+  }
+
+  /// Normal memory that is inner shareable and write-back cacheable but does not allocate cache lines on a read miss
+  /// (MAIR byte ``0xDD``). Data that is only written, like a streaming write buffer, does not pollute the cache with
+  /// lines that are never read.
+  pub fn normal_wb_no_read_alloc() -> Self {
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR6)
+      .into()
+  }
+
+  /// Normal memory that is inner shareable and write-back cacheable but does not allocate cache lines on a write miss
+  /// (MAIR byte ``0xEE``). Writes to data that is not cached go straight to memory while data read is cached. On a SoC
+  /// supporting the memory tagging extension the slot is occupied by tagged memory, so this falls back to
+  /// ``normal_cached``.
+  pub fn normal_wb_no_write_alloc() -> Self {
+    if features().mte {
+      return Self::normal_cached();
+    }
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR7)
+      .into()
+  }
+
+  /// Normal memory that is inner shareable, write-back cacheable and tagged (MAIR byte ``0xF0``), so the allocation tags
+  /// are checked on access once tag checking is enabled in SCTLR_ELx. This requires the memory tagging extension (see
+  /// ``MmuFeatures::mte``), without it this falls back to ``normal_cached``.
+  pub fn normal_tagged() -> Self {
+    if !features().mte {
+      return Self::normal_cached();
+    }
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR7)
+      .into()
+  }
+
+  /// Normal memory that is not cacheable. This is how the memory shared with the VideoCore is mapped in the initial
+  /// 1:1 mapping.
+  pub fn normal_uncached() -> Self {
+    (TTLB_BLOCKPAGE::AF::SET | TTLB_BLOCKPAGE::SH::INNER | TTLB_BLOCKPAGE::MEMATTR::MAIR3).into()
+  }
+
+  /// Normal memory that is not cacheable and outer shareable, so it is coherent between the ARM cores and the
+  /// VideoCore without any cache maintenance. This is how the buffers of the mailbox property interface need to be
+  /// mapped. The memory is never executable.
+  pub fn mailbox_buffer() -> Self {
+    (TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::OUTER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR3
+      | TTLB_BLOCKPAGE::PXN::with_value(1)
+      | TTLB_BLOCKPAGE::XN::with_value(1))
+    .into()
+  }
+
+  /// Normal, outer shareable memory for the buffers of a DMA engine. A ``coherent`` DMA engine snoops the caches, so the
+  /// buffer is write-back cacheable. Otherwise the buffer is not cacheable, the same as ``mailbox_buffer``, so the DMA
+  /// engine never misses data held in the caches. The memory is never executable.
+  pub fn dma_buffer(coherent: bool) -> Self {
+    if !coherent {
+      return Self::mailbox_buffer();
+    }
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::OUTER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR4
+      | TTLB_BLOCKPAGE::PXN::with_value(1)
+      | TTLB_BLOCKPAGE::XN::with_value(1))
+    .into()
+  }
+
+  /// Device memory with strict ordering (Device-nGnRnE). This is how the peripherals are mapped in the initial 1:1
+  /// mapping.
+  pub fn device() -> Self {
+    Self::device_identity(DeviceOrdering::NGnRnE)
+  }
+
+  /// Device memory with the given ordering constraints as the peripherals are mapped in the initial 1:1 mapping
+  pub(crate) fn device_identity(ordering: DeviceOrdering) -> Self {
+    (TTLB_BLOCKPAGE::AF::SET | TTLB_BLOCKPAGE::SH::INNER | ordering.mair()).into()
+  }
+
+  /// Device memory with the given ordering constraints that is never executable, neither in EL1 nor in EL0
+  pub fn device_ordered(ordering: DeviceOrdering) -> Self {
+    (TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | ordering.mair()
+      | TTLB_BLOCKPAGE::PXN::with_value(1)
+      | TTLB_BLOCKPAGE::XN::with_value(1))
+    .into()
+  }
+
+  /// Device memory that allows re-ordering and early write acknowledgement but no gathering (Device-nGRE). Each access
+  /// is issued with exactly the size given, so it suits peripherals that can not cope with merged accesses but do not
+  /// require strict ordering. The memory is never executable.
+  pub fn device_ngre() -> Self {
+    Self::device_ordered(DeviceOrdering::NGRE)
+  }
+
+  /// Device memory for bulk writes to a device FIFO (Device-GRE). Adjacent writes may be gathered into larger ones and
+  /// re-ordered, which increases the throughput while nothing is cached. It shall not be used for registers where each
+  /// individual access has a side effect. The memory is never executable.
+  pub fn device_bulk() -> Self {
+    Self::device_ordered(DeviceOrdering::GRE)
+  }
+
+  /// Normal write-back cacheable memory containing EL0 code that can be executed but not read from EL0. The access
+  /// permissions deny any EL0 data access (``AP::RW_EL1``) while the unprivileged execute never bit (``XN``) is
+  /// clear. The privileged execute never bit (``PXN``) is set, so the code can not be executed in EL1.
+  ///
+  /// EL1 keeps it's read/write access to the memory. As the memory is not accessible from EL0 it is also not protected
+  /// by PAN unless the enhanced PAN extension (FEAT_EPAN, ARMv8.7) is enabled with ``SCTLR_EL1.EPAN``, which treats
+  /// EL0 executable memory as EL0 accessible. The execute never extension (FEAT_XNX) only applies to stage 2
+  /// translations which are not used by this crate.
+  pub fn execute_only() -> Self {
+    (TTLB_BLOCKPAGE::NS::SET
+      | TTLB_BLOCKPAGE::AF::SET
+      | TTLB_BLOCKPAGE::SH::INNER
+      | TTLB_BLOCKPAGE::MEMATTR::MAIR4
+      | TTLB_BLOCKPAGE::AP::RW_EL1
+      | TTLB_BLOCKPAGE::PXN::with_value(1))
+    .into()
+  }
+
+  /// Whether memory with these attributes can be executed in EL1 or EL0
+  pub fn is_executable(self) -> bool {
+    TTLB_BLOCKPAGE::PXN::from_raw(self.0).value() == 0
+      || TTLB_BLOCKPAGE::XN::from_raw(self.0).value() == 0
+  }
+
+  /// Whether memory with these attributes can be executed in EL0 but neither read nor written from EL0
+  pub fn is_execute_only(self) -> bool {
+    TTLB_BLOCKPAGE::AP::from_raw(self.0).value() == TTLB_BLOCKPAGE::AP::RW_EL1.value()
+      && TTLB_BLOCKPAGE::XN::from_raw(self.0).value() == 0
+  }
+}
+
+impl From<RegisterFieldValue<u64>> for MemoryAttributes {
+  fn from(value: RegisterFieldValue<u64>) -> Self {
+    Self::from_raw(value.raw_value())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn execute_only_is_el0_executable_only() {
+    let raw = MemoryAttributes::execute_only().raw();
+    assert_eq!(
+      TTLB_BLOCKPAGE::AP::from_raw(raw).value(),
+      TTLB_BLOCKPAGE::AP::RW_EL1.value()
+    );
+    assert_eq!(TTLB_BLOCKPAGE::XN::from_raw(raw).value(), 0);
+    assert_eq!(TTLB_BLOCKPAGE::PXN::from_raw(raw).value(), 1);
+    assert!(MemoryAttributes::execute_only().is_execute_only());
+    assert!(!MemoryAttributes::mailbox_buffer().is_execute_only());
+  }
+
+  #[test]
+  fn equality_ignores_the_output_address() {
+    let attributes = MemoryAttributes::normal_cached().raw();
+    let entry = |pa: u64| {
+      (TTLB_BLOCKPAGE::TYPE::BLOCK | TTLB_BLOCKPAGE::ADDR::from_raw(pa)).raw_value() | attributes
+    };
+    assert_eq!(
+      MemoryAttributes::from_raw(entry(0x20_0000)),
+      MemoryAttributes::from_raw(entry(0x3F20_0000))
+    );
+    assert_eq!(
+      MemoryAttributes::from_raw(entry(0x20_0000)),
+      MemoryAttributes::normal_cached()
+    );
+  }
+
+  #[test]
+  fn differs_from_reports_each_field_on_its_own() {
+    let base = MemoryAttributes::normal_cached();
+    let with = |field: RegisterFieldValue<u64>, mask: RegisterFieldValue<u64>| {
+      MemoryAttributes::from_raw((base.raw() & !mask.raw_value()) | field.raw_value())
+    };
+    let cases = [
+      (
+        with(
+          TTLB_BLOCKPAGE::MEMATTR::MAIR3,
+          TTLB_BLOCKPAGE::MEMATTR::from_raw(!0),
+        ),
+        AttrDiff {
+          mair: true,
+          ..AttrDiff::default()
+        },
+      ),
+      (
+        with(TTLB_BLOCKPAGE::AP::RO_EL1, TTLB_BLOCKPAGE::AP::from_raw(!0)),
+        AttrDiff {
+          ap: true,
+          ..AttrDiff::default()
+        },
+      ),
+      (
+        with(TTLB_BLOCKPAGE::SH::OUTER, TTLB_BLOCKPAGE::SH::from_raw(!0)),
+        AttrDiff {
+          sh: true,
+          ..AttrDiff::default()
+        },
+      ),
+      (
+        with(
+          TTLB_BLOCKPAGE::XN::with_value(1),
+          TTLB_BLOCKPAGE::XN::from_raw(!0),
+        ),
+        AttrDiff {
+          xn: true,
+          ..AttrDiff::default()
+        },
+      ),
+      (
+        with(
+          TTLB_BLOCKPAGE::PXN::with_value(1),
+          TTLB_BLOCKPAGE::PXN::from_raw(!0),
+        ),
+        AttrDiff {
+          xn: true,
+          ..AttrDiff::default()
+        },
+      ),
+    ];
+    for (other, expected) in cases.iter() {
+      assert_eq!(base.differs_from(other), *expected);
+      assert_eq!(other.differs_from(&base), *expected);
+    }
+    assert!(!base.differs_from(&base.with_sw_bits(0b1010)).any());
+  }
+
+  #[test]
+  fn device_ngre_uses_the_ngre_slot() {
+    // Device memory is 0b0000_dd00 with dd = 0b10 for nGRE
+    assert_eq!(MAIR_DEVICE_NGRE, 0b0000_1000);
+    let raw = MemoryAttributes::device_ngre().raw();
+    assert_eq!(MemoryAttributes::device_ngre().mair_index(), 5);
+    assert_eq!(TTLB_BLOCKPAGE::XN::from_raw(raw).value(), 1);
+    assert_eq!(TTLB_BLOCKPAGE::PXN::from_raw(raw).value(), 1);
+  }
+
+  /// The (read allocate, write allocate) policy of a MAIR byte of normal write-back non-transient memory with the
+  /// same inner and outer cacheability
+  fn allocation(mair: u64) -> (bool, bool) {
+    assert_eq!(mair >> 4, mair & 0xF, "inner and outer cacheability differ");
+    assert_eq!(mair & 0b1100, 0b1100, "not write-back non-transient");
+    (mair & 0b10 != 0, mair & 0b01 != 0)
+  }
+
+  #[test]
+  fn normal_allocation_variants_use_their_slots() {
+    assert_eq!(MemoryAttributes::normal_wb_no_read_alloc().mair_index(), 6);
+    assert_eq!(allocation(MAIR_NORMAL_WB_NO_READ_ALLOC), (false, true));
+
+    // the host does not report the memory tagging extension, so slot 7 is read allocate only
+    assert_eq!(MemoryAttributes::normal_wb_no_write_alloc().mair_index(), 7);
+    assert_eq!(mair7_normal(), MAIR_NORMAL_WB_NO_WRITE_ALLOC);
+    assert_eq!(allocation(MAIR_NORMAL_WB_NO_WRITE_ALLOC), (true, false));
+
+    // without the memory tagging extension tagged memory falls back to slot 4
+    assert_eq!(MemoryAttributes::normal_tagged().mair_index(), 4);
+    assert_eq!(MAIR_NORMAL_TAGGED, 0b1111_0000);
+    assert_eq!(MemoryAttributes::normal_cached().mair_index(), 4);
+  }
+
+  #[test]
+  fn device_bulk_is_never_executable_gre_memory() {
+    let raw = MemoryAttributes::device_bulk().raw();
+    assert_eq!(
+      TTLB_BLOCKPAGE::MEMATTR::from_raw(raw).value(),
+      TTLB_BLOCKPAGE::MEMATTR::MAIR2.value()
+    );
+    assert_eq!(TTLB_BLOCKPAGE::XN::from_raw(raw).value(), 1);
+    assert_eq!(TTLB_BLOCKPAGE::PXN::from_raw(raw).value(), 1);
+    assert!(!MemoryAttributes::device_bulk().is_executable());
+  }
+}
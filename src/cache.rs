@@ -0,0 +1,131 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Cache Maintenance
+//!
+//! Maintenance operations on the data and instruction caches required while the MMU configuration is changed.
+//!
+
+use super::stats;
+
+/// Clean and invalidate the whole data cache by set/way. All cache levels up to the level of coherency are
+/// maintained, so any dirty cache line is written back to the memory and no stale line remains in the cache.
+///
+/// # Safety
+/// The maintenance by set/way is only guarantied to be complete if no other core is allocating new lines into the
+/// caches while the maintenance is ongoing.
+pub unsafe fn clean_invalidate_data_cache() {
+  stats::update(|stats| stats.cache_maintenance += 1);
+  for_each_set_way(|set_way| aarch64_asm!("dc cisw, $0"::"r"(set_way)::"volatile"));
+  aarch64_asm!(
+    "dsb   sy
+     isb"
+  );
+}
+
+/// Clean and invalidate the data cache lines covering the memory range ``addr..addr + size`` to the point of
+/// coherency. Any dirty line is written back to the memory and no stale line of this range remains in the cache.
+///
+/// # Safety
+/// The memory range need to be mapped with the virtual addresses given.
+pub unsafe fn clean_invalidate_range(addr: usize, size: usize) {
+  stats::update(|stats| stats.cache_maintenance += 1);
+  // the smallest data cache line size is given in words as log2 in CTR_EL0.DminLine
+  let ctr: u64;
+  aarch64_asm!("mrs $0, ctr_el0" : "=r"(ctr) ::: "volatile");
+  let line_size = 4 << ((ctr >> 16) & 0xF);
+
+  let mut line = addr & !(line_size - 1);
+  while line < addr + size {
+    aarch64_asm!("dc civac, $0"::"r"(line)::"volatile");
+    line += line_size;
+  }
+  aarch64_asm!("dsb   sy");
+}
+
+/// Invalidate the whole instruction cache to the point of unification
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the caches
+pub unsafe fn invalidate_instruction_cache() {
+  stats::update(|stats| stats.cache_maintenance += 1);
+  aarch64_asm!(
+    "ic    iallu
+     dsb   sy
+     isb"
+  );
+}
+
+/// Synchronize the instruction cache with the code written to the memory range ``va..va + size``. The data cache lines
+/// are cleaned to the point of unification and the instruction cache lines are invalidated afterwards, so the code
+/// executed from this range is the code just written, for example through a writable alias of the memory.
+///
+/// # Safety
+/// The memory range need to be mapped with the virtual addresses given.
+pub unsafe fn sync_instruction_cache(va: *const u8, size: usize) {
+  stats::update(|stats| stats.cache_maintenance += 1);
+  // the smallest data and instruction cache line sizes are given in words as log2 in CTR_EL0.DminLine/IminLine
+  let ctr: u64;
+  aarch64_asm!("mrs $0, ctr_el0" : "=r"(ctr) ::: "volatile");
+  let dline_size = 4 << ((ctr >> 16) & 0xF);
+  let iline_size = 4 << (ctr & 0xF);
+  let addr = va as usize;
+
+  let mut line = addr & !(dline_size - 1);
+  while line < addr + size {
+    aarch64_asm!("dc cvau, $0"::"r"(line)::"volatile");
+    line += dline_size;
+  }
+  aarch64_asm!("dsb   ish");
+  let mut line = addr & !(iline_size - 1);
+  while line < addr + size {
+    aarch64_asm!("ic ivau, $0"::"r"(line)::"volatile");
+    line += iline_size;
+  }
+  aarch64_asm!(
+    "dsb   ish
+     isb"
+  );
+}
+
+/// Call the given function with the set/way operand of each data or unified cache line of all cache levels up to the
+/// level of coherency
+unsafe fn for_each_set_way(f: impl Fn(u64)) {
+  // the cache level ID register tells the cache types implemented at each level and the level of coherency
+  let clidr: u64;
+  aarch64_asm!("mrs $0, clidr_el1" : "=r"(clidr) ::: "volatile");
+  let loc = (clidr >> 24) & 0x7;
+
+  for level in 0..loc {
+    // skip levels without a data or unified cache
+    let cache_type = (clidr >> (level * 3)) & 0x7;
+    if cache_type < 2 {
+      continue;
+    }
+
+    // select the data cache of this level to read it's geometry
+    let ccsidr: u64;
+    aarch64_asm!("msr csselr_el1, $1
+               isb
+               mrs $0, ccsidr_el1" : "=r"(ccsidr) : "r"(level << 1) :: "volatile");
+    let line_shift = (ccsidr & 0x7) + 4;
+    let ways = ((ccsidr >> 3) & 0x3FF) as u32 + 1;
+    let sets = (ccsidr >> 13) & 0x7FFF;
+    // the way number is stored in the upper most bits of the set/way operand
+    let way_shift = if ways > 1 {
+      (ways - 1).leading_zeros()
+    } else {
+      0
+    };
+
+    for way in 0..ways as u64 {
+      for set in 0..=sets {
+        f((way << way_shift) | (set << line_shift) | (level << 1));
+      }
+    }
+  }
+}
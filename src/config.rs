@@ -59,7 +59,13 @@
 //!  NS      | Non-Secure bit specifies whether the output address is in secure or non-secure address map.
 //!  MemAttr | Stage 1 memory attributes - index into MAIR_ELx register
 
-use super::define_tlb_entry;
+use super::{
+  attributes::DeviceOrdering,
+  error::MmuError,
+  features::{features, parange},
+  ttbr0::LOCAL_PERIPHERAL_SIZE,
+};
+use core::ops::Range;
 use ruspiro_arch_aarch64::{RegisterField, RegisterFieldValue};
 
 pub const SECTION_SIZE: usize = 0x20_0000; // 2MB section size
@@ -69,6 +75,371 @@ pub const PAGE_SIZE: usize = 0x1000; // 4kB page size
 pub const PAGE_SHIFT: usize = 12;
 pub const PAGE_MASK: usize = PAGE_SIZE - 1;
 
+/// The value of the T0SZ and T1SZ fields of the TCR_ELx register. It defines the size of the virtual address range
+/// covered by TTBR0 and TTBR1 => 2^(64-TxSZ)
+pub const TXSZ: u64 = 25;
+/// The number of virtual address bits resolved by the translation tables
+pub const VA_BITS: usize = 64 - TXSZ as usize;
+/// The default number of physical address bits configured with the IPS/PS field of the TCR_ELx register
+pub const PA_BITS: usize = 32;
+/// The default start address of the "device" memory region of the peripherals (BCM2837, Raspberry Pi 3)
+pub const PERIPHERAL_BASE: u32 = 0x3F00_0000;
+/// The default size of the "device" memory region of the peripherals (BCM2837, Raspberry Pi 3)
+pub const PERIPHERAL_SIZE: u32 = 0x0100_0000;
+/// The default size of the RAM (Raspberry Pi 3)
+pub const RAM_SIZE: usize = 0x4000_0000;
+
+/// The translation granule a translation region (TTBR0 or TTBR1) is configured with. The granule defines the size of
+/// the smallest page, the number of entries of each translation table and the memory range an entry of a specific
+/// table level covers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Granule {
+  /// 4kB pages, level 1 entries covering 1GB, level 2 entries covering 2MB
+  _4KB,
+  /// 16kB pages, level 2 entries covering 32MB
+  _16KB,
+  /// 64kB pages, level 2 entries covering 512MB
+  _64KB,
+}
+
+impl Granule {
+  /// The number of address bits covered by one page
+  pub const fn page_shift(self) -> usize {
+    match self {
+      Granule::_4KB => 12,
+      Granule::_16KB => 14,
+      Granule::_64KB => 16,
+    }
+  }
+
+  /// The size of one page in bytes
+  pub const fn page_size(self) -> usize {
+    1 << self.page_shift()
+  }
+
+  /// The number of entries of a translation table. A table always occupies exactly one page.
+  pub(crate) const fn table_entries(self) -> usize {
+    1 << (self.page_shift() - 3)
+  }
+
+  /// The number of address bits covered by one entry of the given table level
+  pub(crate) const fn level_shift(self, level: usize) -> usize {
+    self.page_shift() + (3 - level) * (self.page_shift() - 3)
+  }
+
+  /// The number of address bits covered by a level 2 block entry
+  pub(crate) const fn block_shift(self) -> usize {
+    self.level_shift(2)
+  }
+
+  /// Whether block entries are supported at the given table level. With a 4kB granule level 1 and level 2 entries may
+  /// be blocks, with the larger granules only level 2 entries may be.
+  pub(crate) const fn supports_block(self, level: usize) -> bool {
+    match self {
+      Granule::_4KB => level == 1 || level == 2,
+      _ => level == 2,
+    }
+  }
+
+  /// The number of adjacent page entries that form a group sharing one cached translation if the contiguous hint is
+  /// set in each of them
+  pub(crate) const fn contiguous_pages(self) -> usize {
+    match self {
+      Granule::_4KB => 16,
+      Granule::_16KB => 128,
+      Granule::_64KB => 32,
+    }
+  }
+
+  /// The table level the translation table walk starts at. This is the first level that resolves the upper bits of
+  /// the ``va_bits`` wide virtual address range
+  pub(crate) const fn start_level(self, va_bits: usize) -> usize {
+    let mut level = 3;
+    while self.level_shift(level) + self.page_shift() - 3 < va_bits {
+      level -= 1;
+    }
+    level
+  }
+
+  /// The number of entries of the table the translation table walk of a ``va_bits`` wide virtual address range starts
+  /// at
+  pub(crate) const fn root_entries(self, va_bits: usize) -> usize {
+    1 << (va_bits - self.level_shift(self.start_level(va_bits)))
+  }
+}
+
+/// How the access flag of the block and page entries of an address region is maintained. The hardware management
+/// of the access flag is enabled for the whole translation regime, so an address region using ``Software`` will see
+/// the access flag set by the hardware as well, as soon as the other region uses ``Hardware``.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessFlag {
+  /// The access flag is set when an entry is written, so the first access never faults
+  Always,
+  /// The access flag is cleared when an entry is written and set by the hardware on the first access
+  /// (ARMv8.1-TTHM). Falls back to ``Always`` if the hardware does not manage the access flag.
+  Hardware,
+  /// The access flag is cleared when an entry is written and the first access raises an access flag fault. The
+  /// exception handler is expected to set the access flag to resolve it, allowing to account the accessed memory.
+  Software,
+}
+
+impl AccessFlag {
+  /// The access flag handling actually used on the running SoC
+  pub(crate) fn effective(self) -> Self {
+    match self {
+      AccessFlag::Hardware if !features().hardware_access_flag => AccessFlag::Always,
+      _ => self,
+    }
+  }
+}
+
+/// The configuration the MMU is initialized with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmuInitConfig {
+  /// Start address of the memory region used by the VideoCore
+  pub vc_mem_start: u32,
+  /// Size of the memory region used by the VideoCore
+  pub vc_mem_size: u32,
+  /// The granule of the translation tables of the lower (TTBR0) address region
+  pub tg0: Granule,
+  /// The granule of the translation tables of the upper (TTBR1) address region. This is only used in EL1.
+  pub tg1: Granule,
+  /// Limit the identity mapping of the lower (TTBR0) address region to the given physical address range, extended to
+  /// 2MB boundaries. Any access outside this range faults, except for the peripherals which are always mapped as
+  /// "device" memory. ``None`` maps the whole memory.
+  pub identity_range: Option<Range<usize>>,
+  /// Enable the translation table walks of the lower (TTBR0) address region. If disabled no identity mapping is set
+  /// up and any access to the lower address region faults. This is only used in EL1 as the EL2 translation regime
+  /// consists of the lower address region only.
+  pub enable_ttbr0: bool,
+  /// The maximum number of level 2 blocks of the upper (TTBR1) address region a single call to ``map_memory`` may use.
+  /// This guards against a bogus size using up the whole virtual address range. Defaults to ``usize::MAX``, which does
+  /// not limit the mappings.
+  pub max_blocks_per_map: usize,
+  /// Map uniformly attributed 1GB regions of the identity mapping with a single level 1 block entry instead of a level
+  /// 2 table of 2MB blocks. This saves translation table memory and TLB entries. Only the 1GB regions containing the
+  /// VideoCore memory or the peripherals are still maintained with a level 2 table. This is only used with the 4kB
+  /// granule as the larger granules do not support level 1 blocks.
+  pub level1_blocks: bool,
+  /// The access flag handling of the lower (TTBR0) address region. The identity mapping is performance critical, so
+  /// it defaults to ``AccessFlag::Always``.
+  pub ttbr0_af: AccessFlag,
+  /// The access flag handling of the upper (TTBR1) address region. Defaults to ``AccessFlag::Always``.
+  pub ttbr1_af: AccessFlag,
+  /// The base address of a device mapped PL011 UART the panics of the MMU layer are written to directly, polling the
+  /// transmit FIFO. This keeps them visible while the higher level console is not available. ``None`` does not emit
+  /// the panics.
+  pub panic_uart_base: Option<usize>,
+  /// The physical address of a "poison" page all otherwise unmapped virtual address blocks of the upper (TTBR1)
+  /// address region are mapped to read-only. The page is filled with 0xDE when the MMU is initialized, so a stray
+  /// access to an unmapped virtual address reads recognizable garbage instead of faulting. This is meant as debugging
+  /// aid to hunt use-after-unmap bugs. ``None`` lets those accesses fault.
+  pub ttbr1_sentinel: Option<usize>,
+  /// Leave the first page of the identity mapping (the page at address 0, 4kB with the default granule) unmapped, so
+  /// any null or near-null pointer dereference faults. The block containing the first page is split into pages, the
+  /// rest of it stays "normal" memory. No code or data shall be located in the first page if this is enabled.
+  pub trap_null: bool,
+  /// Size of the physical RAM. The RAM below the peripherals is mapped up to the peripherals, the RAM exceeding the
+  /// first 4GB, like the upper half of a 8GB Raspberry Pi 4, is mapped from 4GB onwards. Mapping RAM beyond 4GB
+  /// requires to raise ``pa_bits`` accordingly. Defaults to ``RAM_SIZE`` (1GB).
+  pub ram_size: usize,
+  /// Start address of the "device" memory region of the peripherals. The identity mapping maps the RAM below as
+  /// "normal" memory. Defaults to ``PERIPHERAL_BASE`` (``0x3F00_0000``), a Raspberry Pi 4 uses ``0xFE00_0000``.
+  pub peripheral_base: u32,
+  /// Size of the "device" memory region of the peripherals. The 2MB of ARM local peripherals, like the core mailboxes,
+  /// follow right after this region. Defaults to ``PERIPHERAL_SIZE`` (``0x0100_0000``), a Raspberry Pi 4 uses
+  /// ``0x0180_0000``.
+  pub peripheral_size: u32,
+  /// The ordering of the "device" memory the VideoCore peripherals (``peripheral_base..peripheral_base +
+  /// peripheral_size``) are mapped with in the identity mapping. Defaults to ``DeviceOrdering::NGnRnE``.
+  pub peripheral_ordering: DeviceOrdering,
+  /// The ordering of the "device" memory the ARM local peripherals (the 2MB following the peripherals), like the core
+  /// mailboxes, are mapped with in the identity mapping. A relaxed ordering may lower the latency of the inter
+  /// processor interrupts raised through the mailboxes. Defaults to ``DeviceOrdering::NGnRnE``.
+  pub local_peripheral_ordering: DeviceOrdering,
+  /// Maintain a shadow copy of every translation table entry written, allowing ``verify_shadow`` to detect translation
+  /// tables corrupted by rogue code or hardware errors. The shadow doubles the cost of each entry update and requires
+  /// the ``shadow-tables`` feature, which reserves the memory of the shadow copy.
+  pub shadow_tables: bool,
+  /// Defer the setup of the translation tables of the upper (TTBR1) address region to the first call to
+  /// ``map_memory``. This saves the cost of the setup at initialization if no mapping is needed early, but the first
+  /// ``map_memory`` call becomes more expensive as it sets up the tables and enables the table walks of the upper
+  /// address region. Each core enables the table walks with it's own first call to ``map_memory``, so a core shall not
+  /// access any mapping before it called ``map_memory`` at least once. Only applies to EL1.
+  pub lazy_ttbr1: bool,
+  /// The number of physical address bits configured with the IPS/PS field of the TCR_ELx register. One of 32, 36, 40,
+  /// 42, 44 or 48. Defaults to ``PA_BITS``.
+  pub pa_bits: u8,
+  /// Clamp ``pa_bits`` to the physical address range the SoC supports (``supported_pa_bits``) instead of reporting
+  /// ``MmuError::PhysicalAddressSizeUnsupported`` if more bits are requested than supported
+  pub clamp_pa_bits: bool,
+  /// The number of cores that are active and share the translation tables when the MMU is initialized. With a single
+  /// active core the TLB maintenance is done for the current core only, otherwise it is broadcast to all cores of the
+  /// inner shareable domain. Use ``set_active_cores`` once further cores come online. Defaults to 4.
+  pub active_cores: u32,
+}
+
+impl MmuInitConfig {
+  /// Create the default MMU configuration for the given VideoCore memory region using a 4kB granule for both address
+  /// regions
+  pub const fn new(vc_mem_start: u32, vc_mem_size: u32) -> Self {
+    Self {
+      vc_mem_start,
+      vc_mem_size,
+      tg0: Granule::_4KB,
+      tg1: Granule::_4KB,
+      identity_range: None,
+      enable_ttbr0: true,
+      max_blocks_per_map: usize::MAX,
+      level1_blocks: false,
+      ttbr0_af: AccessFlag::Always,
+      ttbr1_af: AccessFlag::Always,
+      panic_uart_base: None,
+      ttbr1_sentinel: None,
+      trap_null: false,
+      ram_size: RAM_SIZE,
+      peripheral_base: PERIPHERAL_BASE,
+      peripheral_size: PERIPHERAL_SIZE,
+      peripheral_ordering: DeviceOrdering::NGnRnE,
+      local_peripheral_ordering: DeviceOrdering::NGnRnE,
+      shadow_tables: false,
+      lazy_ttbr1: false,
+      pa_bits: PA_BITS as u8,
+      clamp_pa_bits: false,
+      active_cores: 4,
+    }
+  }
+
+  /// The number of physical address bits this configuration resolves to on the running SoC, with ``pa_bits`` clamped
+  /// to the supported physical address range if ``clamp_pa_bits`` is set
+  ///
+  /// # Errors
+  /// ``MmuError::PhysicalAddressSizeUnsupported`` if ``pa_bits`` exceeds the supported physical address range and
+  /// ``clamp_pa_bits`` is not set
+  pub(crate) fn effective_pa_bits(&self) -> Result<u8, MmuError> {
+    let supported = features().pa_bits;
+    if self.pa_bits <= supported {
+      Ok(self.pa_bits)
+    } else if self.clamp_pa_bits {
+      Ok(supported)
+    } else {
+      Err(MmuError::PhysicalAddressSizeUnsupported)
+    }
+  }
+
+  /// The value of the IPS/PS field of the TCR_ELx register for the physical address size of this configuration. Falls
+  /// back to 32 bits for a configuration that does not pass ``validate_config``.
+  pub(crate) fn ips(&self) -> u64 {
+    self.effective_pa_bits().ok().and_then(parange).unwrap_or(0)
+  }
+
+  /// Whether this configuration differs from the ``applied`` one only in fields that can be changed while the MMU
+  /// stays active. These are the fields the identity mapping is derived from (the VideoCore memory, the identity
+  /// range, the first page trap, the RAM size and the location and ordering of the peripherals) and those applied
+  /// without touching the translation regime (the shadow tables, the panic UART and the active cores).
+  pub(crate) fn is_live_update_of(&self, applied: &MmuInitConfig) -> bool {
+    let unchanged = MmuInitConfig {
+      vc_mem_start: applied.vc_mem_start,
+      vc_mem_size: applied.vc_mem_size,
+      identity_range: applied.identity_range.clone(),
+      trap_null: applied.trap_null,
+      ram_size: applied.ram_size,
+      peripheral_base: applied.peripheral_base,
+      peripheral_size: applied.peripheral_size,
+      peripheral_ordering: applied.peripheral_ordering,
+      local_peripheral_ordering: applied.local_peripheral_ordering,
+      shadow_tables: applied.shadow_tables,
+      panic_uart_base: applied.panic_uart_base,
+      active_cores: applied.active_cores,
+      ..self.clone()
+    };
+    unchanged == *applied
+  }
+}
+
+/// Check the cross-field invariants of the given MMU initialization configuration and whether the running SoC supports
+/// it. ``initialize_with`` validates the configuration before it is applied, this allows to check it up front.
+///
+/// # Errors
+/// ``MmuError::InvalidConfig`` naming the first field that is invalid or not supported:
+/// - ``tg0``/``tg1`` if the granule is not supported or can not resolve the virtual address range with a single root
+///   table
+/// - ``peripheral_base``/``peripheral_size`` if the peripherals are not 2MB aligned, empty or the ARM local peripherals
+///   following them exceed the physical address range
+/// - ``vc_mem_start``/``vc_mem_size`` if the VideoCore memory starts within the peripherals or extends into them
+/// - ``ram_size`` if there is no RAM or it exceeds the physical address range or the identity mapped address range
+/// - ``identity_range`` if the range is empty or exceeds the physical address range
+/// - ``max_blocks_per_map`` if no mapping would be allowed at all
+/// - ``ttbr1_sentinel`` if the poison page exceeds the physical address range
+/// - ``pa_bits`` if the number of bits can not be configured with the IPS/PS field
+/// - ``active_cores`` if no core is active
+/// - ``shadow_tables`` if the shadow tables are enabled without the ``shadow-tables`` feature
+///
+/// ``MmuError::PhysicalAddressSizeUnsupported`` if ``pa_bits`` exceeds the physical address range the SoC supports and
+/// ``clamp_pa_bits`` is not set.
+pub fn validate_config(config: &MmuInitConfig) -> Result<(), MmuError> {
+  let features = features();
+  let granule_valid = |granule: Granule| {
+    features.supports_granule(granule) && granule.root_entries(VA_BITS) <= granule.table_entries()
+  };
+  if !granule_valid(config.tg0) {
+    return Err(MmuError::InvalidConfig("tg0"));
+  }
+  if !granule_valid(config.tg1) {
+    return Err(MmuError::InvalidConfig("tg1"));
+  }
+
+  // the physical addresses are limited by the configured IPS and the PARange the SoC supports, configuring an IPS
+  // larger than the PARange is UNPREDICTABLE
+  if parange(config.pa_bits).is_none() {
+    return Err(MmuError::InvalidConfig("pa_bits"));
+  }
+  let pa_limit = 1u64 << config.effective_pa_bits()?;
+  let peripheral_end = config.peripheral_base as u64 + config.peripheral_size as u64;
+  if config.peripheral_base as usize & SECTION_MASK != 0
+    || peripheral_end + LOCAL_PERIPHERAL_SIZE as u64 > pa_limit
+  {
+    return Err(MmuError::InvalidConfig("peripheral_base"));
+  }
+  if config.peripheral_size == 0 || config.peripheral_size as usize & SECTION_MASK != 0 {
+    return Err(MmuError::InvalidConfig("peripheral_size"));
+  }
+  if config.vc_mem_start >= config.peripheral_base {
+    return Err(MmuError::InvalidConfig("vc_mem_start"));
+  }
+  // the VideoCore memory is located below the peripherals and shall not run into them
+  match config.vc_mem_start.checked_add(config.vc_mem_size) {
+    Some(vc_mem_end) if vc_mem_end <= config.peripheral_base => (),
+    _ => return Err(MmuError::InvalidConfig("vc_mem_size")),
+  }
+  if config.ram_size == 0
+    || config.ram_size as u64 > pa_limit
+    || config.ram_size > 1 << (VA_BITS - 1)
+  {
+    return Err(MmuError::InvalidConfig("ram_size"));
+  }
+  if let Some(range) = config.identity_range.as_ref() {
+    if range.start >= range.end || range.end as u64 > pa_limit {
+      return Err(MmuError::InvalidConfig("identity_range"));
+    }
+  }
+  if config.active_cores == 0 {
+    return Err(MmuError::InvalidConfig("active_cores"));
+  }
+  if config.shadow_tables && !cfg!(feature = "shadow-tables") {
+    return Err(MmuError::InvalidConfig("shadow_tables"));
+  }
+  if config.max_blocks_per_map == 0 {
+    return Err(MmuError::InvalidConfig("max_blocks_per_map"));
+  }
+  if let Some(poison) = config.ttbr1_sentinel {
+    if poison as u64 >= pa_limit {
+      return Err(MmuError::InvalidConfig("ttbr1_sentinel"));
+    }
+  }
+  Ok(())
+}
+
 define_tlb_entry![
     /// # TTLB Table Entry format.
     ///
@@ -121,10 +492,21 @@ define_tlb_entry![
         NS OFFSET(5) [
             SET = 0b1
         ],
-        //// data Access Permission bits for AP\[2..1\], AP\[0\] is not defined in the TLB entries
-        AP OFFSET(6) BITS(2),
+        //// data Access Permission bits for AP\[2..1\], AP\[0\] is not defined in the TLB entries. The EL0 accessible
+        //// encodings are protected from EL1 data access while PSTATE.PAN is set (see ``set_pan``).
+        AP OFFSET(6) BITS(2) [
+            /// read/write access from EL1, no access from EL0
+            RW_EL1 = 0b00,
+            /// read/write access from EL1 and EL0, no EL1 access while PAN is set
+            RW_EL1_EL0 = 0b01,
+            /// read-only access from EL1, no access from EL0
+            RO_EL1 = 0b10,
+            /// read-only access from EL1 and EL0, no EL1 access while PAN is set
+            RO_EL1_EL0 = 0b11
+        ],
         /// Shareability flag
         SH OFFSET(8) BITS(2) [
+            OUTER = 0b10,
             INNER = 0b11
         ],
         /// Access Flag bit
@@ -133,7 +515,9 @@ define_tlb_entry![
         ],
         /// not Global bit determines whether this entry is globally valid or only for the current ASID value. This
         /// bit is only valid in EL1 & EL0
-        NG OFFSET(11),
+        NG OFFSET(11) [
+            SET = 0b1
+        ],
         /// Output address - bits \[47:12\] are used if this is a page entry.
         /// Output address - bits \[47:30\] are used if this is a block entry.
         ADDR OFFSET(12) BITS(36),
@@ -144,6 +528,8 @@ define_tlb_entry![
         /// is RES0
         PXN OFFSET(53),
         /// eXecute Never bit determining whether the memory region is executable or not.
-        XN OFFSET(54)
+        XN OFFSET(54),
+        /// Bits reserved for software use, they are ignored by the MMU
+        SW OFFSET(55) BITS(4)
     }
 ];
@@ -13,7 +13,20 @@ use ruspiro_arch_aarch64::{
   register::el1::{mair_el1, sctlr_el1, tcr_el1, ttbr0_el1, ttbr1_el1},
 };
 
-pub fn enable_mmu(ttbr0_addr: u64, ttbr1_addr: u64) {
+use super::config::{AccessFlag, Granule, MmuInitConfig, TXSZ};
+use super::{
+  attributes::{mair7_normal, MAIR_DEVICE_NGRE, MAIR_NORMAL_WB_NO_READ_ALLOC},
+  cache, tlb, trace, MmuState,
+};
+
+/// TCR_EL1.EPD1, disables the table walks of the upper address region if set
+const TCR_EPD1: u64 = 1 << 23;
+/// The position of TCR_EL1.T1SZ
+const TCR_T1SZ_SHIFT: u64 = 16;
+/// TCR_EL1.T1SZ, the size offset of the upper address region
+const TCR_T1SZ_MASK: u64 = 0x3F << TCR_T1SZ_SHIFT;
+
+pub fn enable_mmu(ttbr0_addr: u64, ttbr1_addr: u64, config: &MmuInitConfig) {
   // configure the MAIR (memory attribute) variations we will support
   // those entries are referred to as index in the memeory attributes of the
   // table entries
@@ -23,9 +36,9 @@ pub fn enable_mmu(ttbr0_addr: u64, ttbr1_addr: u64) {
       | mair_el1::MAIR2::GRE
       | mair_el1::MAIR3::NC
       | mair_el1::MAIR4::NORM
-      | mair_el1::MAIR5::NOWTIWT
-      | mair_el1::MAIR6::NOWTNTIWTNT
-      | mair_el1::MAIR7::NOWBTIWBT,
+      | mair_el1::MAIR5::with_value(MAIR_DEVICE_NGRE)
+      | mair_el1::MAIR6::with_value(MAIR_NORMAL_WB_NO_READ_ALLOC)
+      | mair_el1::MAIR7::with_value(mair7_normal()), // write-back, read-allocate only or tagged
   );
 
   // set the ttlb base address for the 1:1 translation table configuration
@@ -38,25 +51,63 @@ pub fn enable_mmu(ttbr0_addr: u64, ttbr1_addr: u64) {
   // used when setting up the translation table entries covering the region the translation table is located at
   // as the lowest granule is 4kB the translation tables should always cover this 4kB to ensure no other dynamic
   // allocated memory may require a different configuration falling into the same 4kB page
+  // the granule of each address region is configured independently
+  let tg0 = match config.tg0 {
+    Granule::_4KB => tcr_el1::TG0::_4KB,
+    Granule::_16KB => tcr_el1::TG0::_16KB,
+    Granule::_64KB => tcr_el1::TG0::_64KB,
+  };
+  let tg1 = match config.tg1 {
+    Granule::_4KB => tcr_el1::TG1::_4KB,
+    Granule::_16KB => tcr_el1::TG1::_16KB,
+    Granule::_64KB => tcr_el1::TG1::_64KB,
+  };
+  // the table walks of the lower address region might be disabled to let any access to it fault
+  let epd0 = if config.enable_ttbr0 {
+    tcr_el1::EPD0::ENABLE
+  } else {
+    tcr_el1::EPD0::DISABLE
+  };
+  // without translation tables for the upper address region any access to it faults
+  let epd1 = if ttbr1_addr != 0 {
+    tcr_el1::EPD1::ENABLE
+  } else {
+    tcr_el1::EPD1::DISABLE
+  };
   tcr_el1::write(
-    tcr_el1::T0SZ::with_value(25)
-            | tcr_el1::EPD0::ENABLE
+    tcr_el1::T0SZ::with_value(TXSZ)
+            | epd0
             | tcr_el1::IRGN0::NM_IWB_RA_WA
             | tcr_el1::ORGN0::NM_OWB_RA_WA
             | tcr_el1::SH0::IS
-            | tcr_el1::TG0::_4KB
-            | tcr_el1::T1SZ::with_value(25) // makes lower address range 0x0 - 0x7F_FFFF_FFFF
-            | tcr_el1::EPD1::ENABLE
+            | tg0
+            | tcr_el1::T1SZ::with_value(TXSZ) // makes lower address range 0x0 - 0x7F_FFFF_FFFF
+            | epd1
             | tcr_el1::IRGN1::NM_IWB_RA_WA
             | tcr_el1::ORGN1::NM_OWB_RA_WA
             | tcr_el1::SH1::IS
-            | tcr_el1::TG1::_4KB
-            | tcr_el1::IPS::_32BITS
+            | tg1
+            | tcr_el1::IPS::with_value(config.ips())
             | tcr_el1::TBI0::IGNORE,
   );
+  // the hardware management of the access flag (TCR_EL1.HA) applies to both address regions
+  if config.ttbr0_af.effective() == AccessFlag::Hardware
+    || config.ttbr1_af.effective() == AccessFlag::Hardware
+  {
+    unsafe {
+      let tcr: u64;
+      aarch64_asm!("mrs $0, tcr_el1" : "=r"(tcr) ::: "volatile");
+      aarch64_asm!("msr tcr_el1, $0" :: "r"(tcr | 1 << 39) :: "volatile");
+    }
+  }
+  trace::marker("mmu: registers\r\n");
 
   // ensure TCR_EL1 and TTBR0_EL1 changes are seen before MMU is activated
   isb();
+  trace::marker("mmu: isb\r\n");
+  // if the translation tables are broken the core hangs right after the MMU is activated, so this marker without the
+  // post-enable marker pinpoints the failure
+  trace::marker("mmu: pre-enable\r\n");
   // set the SCTRL_EL1 to activate the MMU
   sctlr_el1::write(
     sctlr_el1::M::ENABLE
@@ -70,15 +121,139 @@ pub fn enable_mmu(ttbr0_addr: u64, ttbr1_addr: u64) {
   nop();
   // force MMU changes to be seen by the next instruction
   isb();
+  // the UART is only reachable through the 1:1 mapping of the lower address region
+  if config.enable_ttbr0 {
+    trace::marker("mmu: post-enable\r\n");
+  }
+
+  // only the translation regime of this core has changed, so the flush is local to this core
+  unsafe {
+    aarch64_asm!("tlbi  vmalle1");
+  }
+}
+
+/// Disable the data and instruction caches. Only SCTLR_EL1.C and SCTLR_EL1.I are cleared, the MMU stays enabled.
+pub fn disable_caches() {
+  unsafe {
+    let sctlr: u64;
+    aarch64_asm!("mrs $0, sctlr_el1" : "=r"(sctlr) ::: "volatile");
+    aarch64_asm!("msr sctlr_el1, $0" :: "r"(sctlr & !(1 << 2 | 1 << 12)) :: "volatile");
+  }
+  isb();
+}
 
+/// Switch the upper address region to the given translation tables covering a 2^(64-T1SZ) wide virtual address range
+/// while the MMU is active. The table walks of the upper address region are disabled while the tables and the window
+/// size change, so no translation mixing the old and the new setup can be cached. All cached translations are flushed
+/// before the table walks are enabled again. The other fields of TCR_EL1 are kept.
+pub fn set_ttbr1_window(ttbr1_addr: u64, t1sz: u64) {
+  unsafe {
+    let tcr: u64;
+    aarch64_asm!("mrs $0, tcr_el1" : "=r"(tcr) ::: "volatile");
+    aarch64_asm!("msr tcr_el1, $0" :: "r"(tcr | TCR_EPD1) :: "volatile");
+    isb();
+    ttbr1_el1::write(ttbr1_el1::BADDR::with_value(ttbr1_addr));
+    let tcr = (tcr & !TCR_T1SZ_MASK) | ((t1sz << TCR_T1SZ_SHIFT) & TCR_T1SZ_MASK);
+    aarch64_asm!("msr tcr_el1, $0" :: "r"(tcr | TCR_EPD1) :: "volatile");
+    isb();
+    tlb::invalidate_all();
+    aarch64_asm!("msr tcr_el1, $0" :: "r"(tcr & !TCR_EPD1) :: "volatile");
+  }
+  isb();
+}
+
+/// Enable the table walks of the upper address region starting at the given translation tables if they are still
+/// disabled on this core while the MMU is active
+pub fn enable_ttbr1(ttbr1_addr: u64) {
+  if tcr_el1::read(tcr_el1::EPD1::Field).value() == tcr_el1::EPD1::ENABLE.value() {
+    return;
+  }
+  ttbr1_el1::write(ttbr1_el1::BADDR::with_value(ttbr1_addr));
+  // only EPD1 is cleared, the rest of the active translation regime is kept
+  unsafe {
+    let tcr: u64;
+    aarch64_asm!("mrs $0, tcr_el1" : "=r"(tcr) ::: "volatile");
+    aarch64_asm!("msr tcr_el1, $0" :: "r"(tcr & !TCR_EPD1) :: "volatile");
+  }
+  isb();
+  // only the translation regime of this core has changed, so the flush is local to this core
   unsafe {
-    llvm_asm!("tlbi  vmalle1");
+    aarch64_asm!("tlbi  vmalle1");
   }
 }
 
+/// Switch the lower address region to the translation tables at the given address while the MMU is active. All
+/// cached translations are flushed afterwards.
+pub fn set_ttbr0(ttbr0_addr: u64) {
+  ttbr0_el1::write(ttbr0_el1::BADDR::with_value(ttbr0_addr));
+  isb();
+  unsafe { tlb::invalidate_all() };
+}
+
+/// The configured size of the intermediate physical address range, encoded like the PARange field of the
+/// ID_AA64MMFR0_EL1 register
+pub fn physical_address_size() -> u64 {
+  tcr_el1::read(tcr_el1::IPS::Field).value()
+}
+
+/// Whether the data cache is currently enabled
+pub fn is_dcache_enabled() -> bool {
+  sctlr_el1::read(sctlr_el1::C::Field).value() == sctlr_el1::C::ENABLE.value()
+}
+
+/// Whether the MMU is currently enabled
+pub fn is_mmu_enabled() -> bool {
+  sctlr_el1::read(sctlr_el1::M::Field).value() == sctlr_el1::M::ENABLE.value()
+}
+
 pub fn disable_mmu() {
   sctlr_el1::write(sctlr_el1::M::DISABLE | sctlr_el1::C::DISABLE | sctlr_el1::I::DISABLE);
   unsafe {
-    llvm_asm!("tlbi  vmalle1");
+    aarch64_asm!("tlbi  vmalle1");
+  }
+}
+
+/// Capture the MMU register state of EL1
+pub fn save_state() -> MmuState {
+  let mut state = MmuState {
+    el: 1,
+    ttbr0: 0,
+    ttbr1: 0,
+    tcr: 0,
+    mair: 0,
+    sctlr: 0,
+  };
+  unsafe {
+    aarch64_asm!("mrs $0, ttbr0_el1" : "=r"(state.ttbr0) ::: "volatile");
+    aarch64_asm!("mrs $0, ttbr1_el1" : "=r"(state.ttbr1) ::: "volatile");
+    aarch64_asm!("mrs $0, tcr_el1" : "=r"(state.tcr) ::: "volatile");
+    aarch64_asm!("mrs $0, mair_el1" : "=r"(state.mair) ::: "volatile");
+    aarch64_asm!("mrs $0, sctlr_el1" : "=r"(state.sctlr) ::: "volatile");
   }
+  state
+}
+
+/// Restore the MMU register state of EL1. The data cache is cleaned and the MMU is disabled while the translation
+/// registers are reprogrammed and enabled again with the restored SCTLR_EL1 once all cached translations of this core are
+/// flushed.
+///
+/// # Safety
+/// The translation tables the state refers to need to be valid.
+pub unsafe fn restore_state(state: &MmuState) {
+  // write back any dirty data before the caches are switched off together with the MMU
+  cache::clean_invalidate_data_cache();
+  disable_mmu();
+  isb();
+  aarch64_asm!("msr mair_el1, $0" :: "r"(state.mair) :: "volatile");
+  aarch64_asm!("msr ttbr0_el1, $0" :: "r"(state.ttbr0) :: "volatile");
+  aarch64_asm!("msr ttbr1_el1, $0" :: "r"(state.ttbr1) :: "volatile");
+  aarch64_asm!("msr tcr_el1, $0" :: "r"(state.tcr) :: "volatile");
+  isb();
+  aarch64_asm!(
+    "tlbi  vmalle1
+     dsb   nsh
+     isb"
+  );
+  aarch64_asm!("msr sctlr_el1, $0" :: "r"(state.sctlr) :: "volatile");
+  isb();
 }
@@ -9,11 +9,19 @@
 //!
 
 use ruspiro_arch_aarch64::{
-  instructions::nop,
+  instructions::{isb, nop},
   register::el2::{hcr_el2, mair_el2, sctlr_el2, tcr_el2, ttbr0_el2},
 };
 
-pub fn enable_mmu(ttlb_base_addr: u64) {
+use super::config::{AccessFlag, Granule, MmuInitConfig, TXSZ};
+use super::{
+  attributes::{mair7_normal, MAIR_DEVICE_NGRE, MAIR_NORMAL_WB_NO_READ_ALLOC},
+  cache, tlb, trace, MmuState,
+};
+
+/// Enable the MMU for the EL2 translation regime. The configuration assumes EL2 runs in the non-secure state, a secure
+/// EL2 would require the secure translation regime with it's own stage 2 tables (``VSTTBR_EL2``).
+pub fn enable_mmu(ttlb_base_addr: u64, config: &MmuInitConfig) {
   // configure the MAIR (memory attribute) variations we will support
   // those entries are referred to as index in the memeory attributes of the
   // table entries
@@ -22,7 +30,10 @@ pub fn enable_mmu(ttlb_base_addr: u64) {
       | mair_el2::MAIR1::NGNRE
       | mair_el2::MAIR2::GRE
       | mair_el2::MAIR3::NC
-      | mair_el2::MAIR4::NORM,
+      | mair_el2::MAIR4::NORM
+      | mair_el2::MAIR5::with_value(MAIR_DEVICE_NGRE)
+      | mair_el2::MAIR6::with_value(MAIR_NORMAL_WB_NO_READ_ALLOC)
+      | mair_el2::MAIR7::with_value(mair7_normal()), // write-back, read-allocate only or tagged
   );
 
   // set the ttlb base address, this is where the memory address translation
@@ -30,17 +41,40 @@ pub fn enable_mmu(ttlb_base_addr: u64) {
   ttbr0_el2::write(ttbr0_el2::BADDR::with_value(ttlb_base_addr));
 
   // configure the TTLB attributes
+  let tg0 = match config.tg0 {
+    Granule::_4KB => tcr_el2::TG0::_4KB,
+    Granule::_16KB => tcr_el2::TG0::_16KB,
+    Granule::_64KB => tcr_el2::TG0::_64KB,
+  };
+  // the translation tables are written through the write-back cacheable and inner shareable 1:1 mapping, so the table
+  // walk need to access them with the same attributes to see the entries without any cache maintenance
   tcr_el2::write(
-    tcr_el2::T0SZ::with_value(25)
-            | tcr_el2::IRGN0::NM_INC //NM_IWB_RA_WA
-            | tcr_el2::ORGN0::NM_ONC //NM_OWB_RA_WA
-            | tcr_el2::SH0::OS //IS
-            | tcr_el2::TG0::_4KB
-            | tcr_el2::PS::_32BITS
-            | tcr_el2::TBI::IGNORE,
+    tcr_el2::T0SZ::with_value(TXSZ)
+      | tcr_el2::IRGN0::NM_IWB_RA_WA
+      | tcr_el2::ORGN0::NM_OWB_RA_WA
+      | tcr_el2::SH0::IS
+      | tg0
+      | tcr_el2::PS::with_value(config.ips())
+      | tcr_el2::TBI::IGNORE,
   );
+  // let the hardware manage the access flag (TCR_EL2.HA)
+  if config.ttbr0_af.effective() == AccessFlag::Hardware {
+    unsafe {
+      let tcr: u64;
+      aarch64_asm!("mrs $0, tcr_el2" : "=r"(tcr) ::: "volatile");
+      aarch64_asm!("msr tcr_el2, $0" :: "r"(tcr | 1 << 21) :: "volatile");
+    }
+  }
 
   hcr_el2::write(hcr_el2::DC::DISABLE | hcr_el2::VM::DISABLE);
+  trace::marker("mmu: registers\r\n");
+
+  // ensure TCR_EL2 and TTBR0_EL2 changes are seen before MMU is activated
+  isb();
+  trace::marker("mmu: isb\r\n");
+  // if the translation tables are broken the core hangs right after the MMU is activated, so this marker without the
+  // post-enable marker pinpoints the failure
+  trace::marker("mmu: pre-enable\r\n");
 
   // set the SCTRL_EL2 to activate the MMU
   sctlr_el2::write(
@@ -54,15 +88,96 @@ pub fn enable_mmu(ttlb_base_addr: u64) {
   // let 2 cycles pass with a nop to settle the MMU
   nop();
   nop();
+  // force MMU changes to be seen by the next instruction
+  isb();
+  trace::marker("mmu: post-enable\r\n");
+
+  // only the translation regime of this core has changed, so the flush is local to this core
+  unsafe {
+    aarch64_asm!("tlbi  alle2");
+  }
+}
 
+/// Disable the data and instruction caches. Only SCTLR_EL2.C and SCTLR_EL2.I are cleared, the MMU stays enabled.
+pub fn disable_caches() {
   unsafe {
-    llvm_asm!("tlbi  alle2");
+    let sctlr: u64;
+    aarch64_asm!("mrs $0, sctlr_el2" : "=r"(sctlr) ::: "volatile");
+    aarch64_asm!("msr sctlr_el2, $0" :: "r"(sctlr & !(1 << 2 | 1 << 12)) :: "volatile");
   }
+  isb();
+}
+
+/// Switch the translation regime to the translation tables at the given address while the MMU is active. All cached
+/// translations are flushed afterwards.
+pub fn set_ttbr0(ttbr0_addr: u64) {
+  ttbr0_el2::write(ttbr0_el2::BADDR::with_value(ttbr0_addr));
+  isb();
+  unsafe { tlb::invalidate_all() };
+}
+
+/// The configured size of the intermediate physical address range, encoded like the PARange field of the
+/// ID_AA64MMFR0_EL1 register
+pub fn physical_address_size() -> u64 {
+  tcr_el2::read(tcr_el2::PS::Field).value()
+}
+
+/// Whether the data cache is currently enabled
+pub fn is_dcache_enabled() -> bool {
+  sctlr_el2::read(sctlr_el2::C::Field).value() == sctlr_el2::C::ENABLE.value()
+}
+
+/// Whether the MMU is currently enabled
+pub fn is_mmu_enabled() -> bool {
+  sctlr_el2::read(sctlr_el2::M::Field).value() == sctlr_el2::M::ENABLE.value()
 }
 
 pub fn disable_mmu() {
   sctlr_el2::write(sctlr_el2::M::DISABLE | sctlr_el2::C::DISABLE | sctlr_el2::I::DISABLE);
   unsafe {
-    llvm_asm!("tlbi  alle2");
+    aarch64_asm!("tlbi  alle2");
   }
 }
+
+/// Capture the MMU register state of EL2
+pub fn save_state() -> MmuState {
+  let mut state = MmuState {
+    el: 2,
+    ttbr0: 0,
+    ttbr1: 0,
+    tcr: 0,
+    mair: 0,
+    sctlr: 0,
+  };
+  unsafe {
+    aarch64_asm!("mrs $0, ttbr0_el2" : "=r"(state.ttbr0) ::: "volatile");
+    aarch64_asm!("mrs $0, tcr_el2" : "=r"(state.tcr) ::: "volatile");
+    aarch64_asm!("mrs $0, mair_el2" : "=r"(state.mair) ::: "volatile");
+    aarch64_asm!("mrs $0, sctlr_el2" : "=r"(state.sctlr) ::: "volatile");
+  }
+  state
+}
+
+/// Restore the MMU register state of EL2. The data cache is cleaned and the MMU is disabled while the translation
+/// registers are reprogrammed and enabled again with the restored SCTLR_EL2 once all cached translations of this core are
+/// flushed.
+///
+/// # Safety
+/// The translation tables the state refers to need to be valid.
+pub unsafe fn restore_state(state: &MmuState) {
+  // write back any dirty data before the caches are switched off together with the MMU
+  cache::clean_invalidate_data_cache();
+  disable_mmu();
+  isb();
+  aarch64_asm!("msr mair_el2, $0" :: "r"(state.mair) :: "volatile");
+  aarch64_asm!("msr ttbr0_el2, $0" :: "r"(state.ttbr0) :: "volatile");
+  aarch64_asm!("msr tcr_el2, $0" :: "r"(state.tcr) :: "volatile");
+  isb();
+  aarch64_asm!(
+    "tlbi  alle2
+     dsb   nsh
+     isb"
+  );
+  aarch64_asm!("msr sctlr_el2, $0" :: "r"(state.sctlr) :: "volatile");
+  isb();
+}
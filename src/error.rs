@@ -0,0 +1,130 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # MMU Errors
+//!
+//! Some errors carry the context of the failure, like the offending address, in debug builds. The context is compiled
+//! out of release builds to keep the error small, the ``Display`` implementation renders it if available.
+//!
+
+use core::fmt;
+
+/// The errors the MMU functions may report
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MmuError {
+  /// The mapping of a zero sized type was requested
+  ZeroSizedType,
+  /// The address given is not aligned as required
+  Unaligned {
+    /// The address that is not aligned
+    #[cfg(debug_assertions)]
+    addr: usize,
+    /// The alignment the address is required to have
+    #[cfg(debug_assertions)]
+    align: usize,
+  },
+  /// The size of the requested mapping exceeds the address range
+  SizeOverflow,
+  /// There is no virtual address region available in the current exception level, as only EL1 provides the upper
+  /// (TTBR1) address region
+  NoVirtualMapping,
+  /// The ASID given exceeds the 8 bits supported by the MMU configuration
+  InvalidAsid,
+  /// There is no memory left to provide a further translation table
+  TableMemoryExhausted,
+  /// The requested mapping covers the memory of the translation tables with memory attributes that differ from those
+  /// the translation table walk is configured with
+  WouldCorruptTables,
+  /// The address range is not completely mapped
+  NotMapped,
+  /// The function is not supported in the given exception level
+  UnsupportedExceptionLevel(u8),
+  /// There is no virtual address range left to map the memory to
+  AddressSpaceExhausted {
+    /// The size of the virtual address range requested
+    #[cfg(debug_assertions)]
+    size: usize,
+    /// The number of free virtual address blocks left
+    #[cfg(debug_assertions)]
+    free_blocks: usize,
+  },
+  /// The requested size of the virtual address range is not supported
+  InvalidWindowSize,
+  /// An existing mapping is not covered by the requested virtual address range
+  MappingOutsideWindow,
+  /// The requested mapping would use more level 2 blocks than a single mapping is allowed to use
+  RequestTooLarge,
+  /// There is no free virtual address range with the requested alignment
+  AlignmentUnsatisfiable,
+  /// The buffer given is too small to hold the requested data
+  BufferTooSmall,
+  /// The translation granule does not support block entries of the requested size
+  UnsupportedBlockSize,
+  /// The size of a mapping done with block entries only is not a multiple of the block size
+  SizeNotBlockAligned,
+  /// The requested physical address size exceeds the physical address range supported by the SoC
+  PhysicalAddressSizeUnsupported,
+  /// The physical memory region covers the peripherals or the VideoCore memory which can not be mapped cacheable
+  InvalidPhysicalRegion,
+  /// The field of the MMU initialization configuration with the given name is invalid or not supported by the running
+  /// SoC
+  InvalidConfig(&'static str),
+  /// The translation tables have been set up already with a different configuration, they are only set up again after
+  /// ``deinitialize``
+  AlreadyInitialized,
+}
+
+impl MmuError {
+  /// The error for an address that is not aligned to ``align`` bytes
+  pub(crate) fn unaligned(addr: usize, align: usize) -> Self {
+    #[cfg(not(debug_assertions))]
+    let _ = (addr, align);
+    MmuError::Unaligned {
+      #[cfg(debug_assertions)]
+      addr,
+      #[cfg(debug_assertions)]
+      align,
+    }
+  }
+
+  /// The error for a virtual address range of ``size`` bytes that does not fit into the ``free_blocks`` blocks left
+  pub(crate) fn address_space_exhausted(size: usize, free_blocks: usize) -> Self {
+    #[cfg(not(debug_assertions))]
+    let _ = (size, free_blocks);
+    MmuError::AddressSpaceExhausted {
+      #[cfg(debug_assertions)]
+      size,
+      #[cfg(debug_assertions)]
+      free_blocks,
+    }
+  }
+}
+
+impl fmt::Display for MmuError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      #[cfg(debug_assertions)]
+      MmuError::Unaligned { addr, align } => {
+        write!(
+          f,
+          "address {:#x} is not aligned to {:#x} bytes",
+          addr, align
+        )
+      }
+      #[cfg(debug_assertions)]
+      MmuError::AddressSpaceExhausted { size, free_blocks } => write!(
+        f,
+        "no virtual address range of {:#x} bytes left, {} free blocks",
+        size, free_blocks
+      ),
+      MmuError::UnsupportedExceptionLevel(el) => write!(f, "unsupported exception level EL{}", el),
+      MmuError::InvalidConfig(field) => write!(f, "invalid configuration field {}", field),
+      // the variants without any context are rendered with their name
+      error => fmt::Debug::fmt(error, f),
+    }
+  }
+}
@@ -0,0 +1,130 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # MMU Feature Detection
+//!
+//! Decode the memory model feature registers ID_AA64MMFR0_EL1, ID_AA64MMFR1_EL1 and ID_AA64MMFR2_EL1 as well as the
+//! processor feature registers ID_AA64PFR0_EL1 and ID_AA64PFR1_EL1 to tell which optional MMU capabilities the running SoC supports.
+//!
+
+use super::config::Granule;
+
+/// The MMU features supported by the running SoC
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MmuFeatures {
+  /// The 4kB translation granule is supported
+  pub granule_4kb: bool,
+  /// The 16kB translation granule is supported
+  pub granule_16kb: bool,
+  /// The 64kB translation granule is supported
+  pub granule_64kb: bool,
+  /// The number of physical address bits supported (PARange)
+  pub pa_bits: u8,
+  /// The number of ASID bits supported
+  pub asid_bits: u8,
+  /// Large physical addresses with 52 bits are supported (LPA)
+  pub lpa: bool,
+  /// The access flag is updated by the hardware
+  pub hardware_access_flag: bool,
+  /// The dirty state is managed by the hardware
+  pub hardware_dirty_state: bool,
+  /// Translation table entries may be shared between cores (CnP)
+  pub cnp: bool,
+  /// The virtualization host extensions are supported (VHE)
+  pub vhe: bool,
+  /// The privileged access never feature is supported (PAN)
+  pub pan: bool,
+  /// EL2 may be enabled in the secure state (SEL2)
+  pub secure_el2: bool,
+  /// Normal memory may be tagged with allocation tags checked on access (MTE2)
+  pub mte: bool,
+}
+
+impl MmuFeatures {
+  /// Whether the given translation granule is supported
+  pub fn supports_granule(&self, granule: Granule) -> bool {
+    match granule {
+      Granule::_4KB => self.granule_4kb,
+      Granule::_16KB => self.granule_16kb,
+      Granule::_64KB => self.granule_64kb,
+    }
+  }
+}
+
+/// Query the MMU features supported by the running SoC
+pub fn features() -> MmuFeatures {
+  let mmfr0: u64;
+  let mmfr1: u64;
+  let mmfr2: u64;
+  let pfr0: u64;
+  let pfr1: u64;
+  unsafe {
+    aarch64_asm!("mrs $0, id_aa64mmfr0_el1" : "=r"(mmfr0) ::: "volatile");
+    aarch64_asm!("mrs $0, id_aa64mmfr1_el1" : "=r"(mmfr1) ::: "volatile");
+    aarch64_asm!("mrs $0, id_aa64mmfr2_el1" : "=r"(mmfr2) ::: "volatile");
+    aarch64_asm!("mrs $0, id_aa64pfr0_el1" : "=r"(pfr0) ::: "volatile");
+    aarch64_asm!("mrs $0, id_aa64pfr1_el1" : "=r"(pfr1) ::: "volatile");
+  }
+
+  let parange = field(mmfr0, 0);
+  MmuFeatures {
+    // TGran4 and TGran64 are 0b0000 if supported, TGran16 is 0b0000 if not supported
+    granule_4kb: field(mmfr0, 28) != 0xF,
+    granule_16kb: field(mmfr0, 20) != 0x0,
+    granule_64kb: field(mmfr0, 24) != 0xF,
+    pa_bits: pa_bits(parange),
+    asid_bits: if field(mmfr0, 4) == 0b0010 { 16 } else { 8 },
+    lpa: parange == 0b0110,
+    hardware_access_flag: field(mmfr1, 0) >= 1,
+    hardware_dirty_state: field(mmfr1, 0) >= 2,
+    cnp: field(mmfr2, 0) >= 1,
+    vhe: field(mmfr1, 8) >= 1,
+    pan: field(mmfr1, 20) >= 1,
+    secure_el2: field(pfr0, 36) >= 1,
+    // the allocation tags are only stored and checked with the full MTE implementation
+    mte: field(pfr1, 8) >= 2,
+  }
+}
+
+/// Decode the PARange value of the ID_AA64MMFR0_EL1 register into the number of supported physical address bits
+pub(crate) fn pa_bits(parange: u64) -> u8 {
+  match parange {
+    0b0000 => 32,
+    0b0001 => 36,
+    0b0010 => 40,
+    0b0011 => 42,
+    0b0100 => 44,
+    0b0101 => 48,
+    _ => 52,
+  }
+}
+
+/// Encode the number of physical address bits as PARange value, which is also the encoding of the IPS/PS field of the
+/// TCR_ELx register. Returns ``None`` if the number of bits can not be configured, 52 bits require the 64kB granule
+/// and are not supported by this crate.
+pub(crate) fn parange(pa_bits: u8) -> Option<u64> {
+  match pa_bits {
+    32 => Some(0b0000),
+    36 => Some(0b0001),
+    40 => Some(0b0010),
+    42 => Some(0b0011),
+    44 => Some(0b0100),
+    48 => Some(0b0101),
+    _ => None,
+  }
+}
+
+/// The number of physical address bits the running SoC supports, decoded from the PARange field of the
+/// ID_AA64MMFR0_EL1 register
+pub fn supported_pa_bits() -> u8 {
+  features().pa_bits
+}
+
+/// Extract the 4 bit wide feature field at the given offset of a feature register
+fn field(register: u64, offset: u64) -> u64 {
+  (register >> offset) & 0xF
+}
@@ -0,0 +1,46 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Mapping Hook
+//!
+//! An optional function that is called after each mapping operation on the translation tables. It allows to log all
+//! mapping activity, for example to trace a memory corruption or to picture the address space usage over time. As
+//! atomic operations are not available while the MMU is disabled, the hook is a plain value that is only checked for
+//! being set, so an unset hook does not burden the maintenance functions.
+//!
+
+/// The kind of mapping operation the hook is called for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapOp {
+  /// A physical memory region has been mapped
+  Map,
+  /// A virtual memory region has been unmapped
+  Unmap,
+  /// The memory attributes of a mapped region have been replaced
+  Remap,
+  /// The access permissions of a mapped region have been restricted
+  Protect,
+}
+
+/// The function called after each mapping operation with the operation, the physical address, the virtual address and
+/// the size of the memory region as well as the raw memory attributes it is mapped with
+pub type MapHook = fn(op: MapOp, pa: usize, va: usize, size: usize, attrs: u64);
+
+/// The hook called after each mapping operation
+static mut MAP_HOOK: Option<MapHook> = None;
+
+/// Set the function called after each mapping operation. ``None`` removes the hook, which is the default.
+pub fn set_map_hook(hook: Option<MapHook>) {
+  unsafe { MAP_HOOK = hook };
+}
+
+/// Call the mapping hook if it is set
+pub(crate) fn notify(op: MapOp, pa: usize, va: usize, size: usize, attrs: u64) {
+  if let Some(hook) = unsafe { MAP_HOOK } {
+    hook(op, pa, va, size, attrs);
+  }
+}
@@ -7,6 +7,8 @@
 #![doc(html_root_url = "https://docs.rs/ruspiro-mmu/||VERSION||")]
 #![cfg_attr(not(any(test, doctest)), no_std)]
 #![feature(llvm_asm)]
+// the inline assembly is not emitted on other targets, leaving the unsafe blocks around it without unsafe operations
+#![cfg_attr(not(target_arch = "aarch64"), allow(unused_unsafe))]
 //#![cfg(target_arch = "aarch64")]
 
 //! # RusPiRo MMU API
@@ -15,92 +17,1502 @@
 //! configured and active MMU is a prerequisit to use any atomic operations.
 //!
 
+use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::slice;
 use ruspiro_arch_aarch64::{register::currentel, register_field, register_field_values};
 
+#[macro_use]
+mod macros;
+
+mod attributes;
+mod cache;
 mod config;
 mod el1;
 mod el2;
-mod macros;
+mod error;
+mod features;
+mod hook;
+mod stats;
+mod tables;
+mod tlb;
+mod trace;
+mod translate;
 mod ttbr0;
 mod ttbr1;
-pub use config::TTLB_BLOCKPAGE;
+mod walk;
+pub use attributes::{AttrDiff, DeviceOrdering, MemoryAttributes};
+pub use cache::sync_instruction_cache;
+pub use config::{validate_config, AccessFlag, Granule, MmuInitConfig, TTLB_BLOCKPAGE};
+pub use error::MmuError;
+pub use features::{features, supported_pa_bits, MmuFeatures};
+pub use hook::{set_map_hook, MapHook, MapOp};
+pub use stats::{stats, MmuStats};
+pub use tables::{ReconfigBuilder, TableBuilder, TableUsage};
+pub use translate::{translate_el1, TranslationFault};
+pub use walk::{walk_attributes, RegionWalk, WalkAttributes, WalkCacheability, WalkShareability};
 
 /// Initialize the MMU. This configures an initial 1:1 mapping accross the whole available
 /// memory of the Raspberry Pi. Only the memory region from 0x3F00_0000 to 0x4002_0000 is configured
 /// as device memory as this is the area the memory mapped peripherals and the core mailboxes are
-/// located at.
+/// located at. Use ``initialize_with`` and ``MmuInitConfig::peripheral_base`` for a different peripheral
+/// location, like the one of the Raspberry Pi 4.
 ///
 /// # Safety
 /// The call to this function is safe when executed as part of the initial setup of the Raspberry Pi kernel and is
 /// called only once for each core.
-pub unsafe fn initialize(core: u32, vc_mem_start: u32, vc_mem_size: u32) {
+/// # Errors
+/// Same as for ``initialize_with``, for example ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2.
+pub unsafe fn initialize(core: u32, vc_mem_start: u32, vc_mem_size: u32) -> Result<(), MmuError> {
+  initialize_with(core, &MmuInitConfig::new(vc_mem_start, vc_mem_size), None)
+}
+
+/// Initialize the MMU with the given configuration. This allows to choose the translation granule of the lower
+/// (TTBR0) and the upper (TTBR1) address region independently. The translation tables of each region are sized
+/// according to it's granule.
+///
+/// The optional ``post_setup`` callback is called on core 0 after the default 1:1 mapping has been set up and before the
+/// MMU is activated. It receives a ``TableBuilder`` to maintain additional initial mappings, like a trampoline page or
+/// a shared mailbox.
+///
+/// Calling this function again once the MMU is enabled with the same configuration has no effect. If the MMU has been
+/// disabled in between, for example with ``teardown``, the translation tables set up before are kept and only the MMU
+/// of the current core is enabled again. In both cases ``post_setup`` is not called again. If no ``post_setup`` is given
+/// and the configuration differs from the one applied before only in ``vc_mem_start``, ``vc_mem_size``,
+/// ``identity_range``, ``trap_null``, ``ram_size``, ``peripheral_base``, ``peripheral_size``, ``peripheral_ordering``,
+/// ``local_peripheral_ordering``, ``shadow_tables``, ``panic_uart_base`` or ``active_cores``, the identity mapping is
+/// updated while the MMU stays active. The translation tables are set up only once, as the other cores may still use
+/// them, so any other change is refused on core 0 with ``MmuError::AlreadyInitialized`` until ``deinitialize`` has
+/// cleared the tables.
+///
+/// If the lower address region is disabled with ``MmuInitConfig::enable_ttbr0`` in EL1 there is no identity mapping
+/// and any access to a low address faults - which reliably catches null pointer dereferences.
+///
+/// The EL2 translation regime is configured for the non-secure state. The security state EL2 is running in is only
+/// visible from EL3, so it is not checked. On a SoC supporting secure EL2 (see ``MmuFeatures::secure_el2``) the
+/// caller has to ensure EL2 is entered in the non-secure state.
+///
+/// The configuration is checked with ``validate_config`` before anything is changed, so an invalid configuration is
+/// reported with ``MmuError::InvalidConfig`` naming the offending field while the current setup stays active.
+///
+/// # Safety
+/// The call to this function is safe when executed as part of the initial setup of the Raspberry Pi kernel and is
+/// called only once for each core with the same configuration. With the lower address region disabled the code
+/// calling this function, it's stack and any memory accessed afterwards need to be located in the upper address
+/// region already.
+pub unsafe fn initialize_with(
+  core: u32,
+  config: &MmuInitConfig,
+  post_setup: Option<&dyn Fn(&mut TableBuilder)>,
+) -> Result<(), MmuError> {
   // the mmu configuration depends on the exception level we are running in
   let el = currentel::read(currentel::EL::Field).value();
+  validate_config(config)?;
+  let enabled = match el {
+    1 => el1::is_mmu_enabled(),
+    2 => el2::is_mmu_enabled(),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  };
+  // the translation tables set up already are kept or updated in place, but never set up again
+  let keeps_tables = matches!(APPLIED_CONFIG.as_ref(), Some(applied) if applied == config
+    || (enabled && post_setup.is_none() && config.is_live_update_of(applied)));
+  if core == 0 && TABLES_INITIALIZED && !keeps_tables {
+    return Err(MmuError::AlreadyInitialized);
+  }
+  // the settings of the configuration are only applied once it is known to be usable
+  trace::set_panic_uart(config.panic_uart_base);
+  trace::set_peripheral_base(config.peripheral_base);
+  if core == 0 {
+    set_active_cores(config.active_cores);
+  }
+
+  // an active MMU is kept as it is or only it's identity mapping is updated
+  if let (true, Some(applied)) = (enabled, APPLIED_CONFIG.as_ref()) {
+    if applied == config {
+      return Ok(());
+    }
+    if post_setup.is_none() && config.is_live_update_of(applied) {
+      // the translation tables are shared by all cores, so they are updated only once
+      if core == 0 {
+        tables::set_shadow(config.shadow_tables);
+        ttbr0::update_identity_mapping(applied, config);
+        APPLIED_CONFIG = Some(config.clone());
+      }
+      return Ok(());
+    }
+  }
+
+  disable_for_setup(el)?;
+
+  // initializing core 0 again with the configuration already applied keeps the translation tables, as the other cores
+  // may still use them
+  let (ttlb0_base_addr, ttlb1_base_addr) = if core == 0 && APPLIED_CONFIG.as_ref() == Some(config) {
+    let ttlb0_base_addr = if el != 1 || config.enable_ttbr0 {
+      ttbr0::mmu_config().root as u64
+    } else {
+      0
+    };
+    let ttlb1_base_addr = if el == 1 {
+      ttbr1::mmu_config().root as u64
+    } else {
+      0
+    };
+    (ttlb0_base_addr, ttlb1_base_addr)
+  } else {
+    setup_tables(core, el, config, post_setup)
+  };
+
+  match el {
+    1 => el1::enable_mmu(ttlb0_base_addr, ttlb1_base_addr, config),
+    2 => el2::enable_mmu(ttlb0_base_addr, config),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+  trace::marker("mmu: enabled\r\n");
+
+  if core == 0 {
+    APPLIED_CONFIG = Some(config.clone());
+  }
+  Ok(())
+}
+
+/// Set up the translation tables of both address regions for the given configuration and return the root tables of
+/// the lower (TTBR0) and the upper (TTBR1) address region. The tables are shared by all cores, so they are only set up
+/// on core 0 while the other cores receive the root tables set up already.
+///
+/// # Safety
+/// The MMU of the current core need to be disabled and no other core shall use the translation tables of core 0.
+unsafe fn setup_tables(
+  core: u32,
+  el: u64,
+  config: &MmuInitConfig,
+  post_setup: Option<&dyn Fn(&mut TableBuilder)>,
+) -> (u64, u64) {
+  // the translation tables are set up from scratch, so start with a clean table memory that may still contain the
+  // tables of a previous run
+  if core == 0 {
+    tables::reset_pool();
+    tables::set_shadow(config.shadow_tables);
+    TABLES_INITIALIZED = true;
+  }
+
+  // setup translation table entries, the lower address region can only be disabled in EL1
+  let ttlb0_base_addr = if el != 1 || config.enable_ttbr0 {
+    ttbr0::setup_translation_tables(core, config) as u64
+  } else {
+    0
+  };
+  trace::marker("mmu: ttbr0 tables\r\n");
+  let ttlb1_base_addr = if el == 1 && config.lazy_ttbr1 {
+    // the upper address region stays disabled until the first mapping sets up it's translation tables
+    ttbr1::defer_translation_tables(core, config) as u64
+  } else if el == 1 {
+    ttbr1::setup_translation_tables(core, config) as u64
+  } else {
+    0
+  };
+  trace::marker("mmu: ttbr1 tables\r\n");
+
+  // the additional initial mappings are maintained only once as the translation tables are shared by all cores
+  if let (0, Some(post_setup)) = (core, post_setup) {
+    post_setup(&mut TableBuilder::new());
+    aarch64_asm!("dsb   ishst");
+  }
+
+  (ttlb0_base_addr, ttlb1_base_addr)
+}
+
+/// Enable the MMU with translation tables entirely provided by the caller, for example precomputed by a bootloader.
+/// No translation table is set up, only the MAIR, TCR, TTBR and SCTLR registers are programmed for the given
+/// configuration with the same sequence ``initialize_with`` uses. Without ``ttbr1_base`` the table walks of the upper
+/// (TTBR1) address region are disabled in EL1, so any access to it faults.
+///
+/// The translation table maintenance functions of this crate are not aware of the caller provided tables and shall
+/// not be used afterwards, ``current_config`` returns ``None`` until ``initialize_with`` sets up the crate's own
+/// tables again.
+///
+/// # Safety
+/// The caller is responsible for the correctness of the translation tables. They need to be coherent with the table
+/// walk and cover the code, the stack and any data accessed afterwards. This need to be called on each core.
+/// # Errors
+/// ``MmuError::InvalidConfig`` if the configuration is not valid (see ``validate_config``),
+/// ``MmuError::Unaligned`` if a base address is not
+/// aligned to the granule of it's address region and ``MmuError::NoVirtualMapping`` if ``ttbr1_base`` is given in
+/// EL2, which does not provide the upper address region.
+pub unsafe fn install_tables(
+  ttbr0_base: u64,
+  ttbr1_base: Option<u64>,
+  config: &MmuInitConfig,
+) -> Result<(), MmuError> {
+  let el = currentel::read(currentel::EL::Field).value();
+  validate_config(config)?;
+  if el == 2 && ttbr1_base.is_some() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  if ttbr0_base & (config.tg0.page_size() as u64 - 1) != 0 {
+    return Err(MmuError::unaligned(
+      ttbr0_base as usize,
+      config.tg0.page_size(),
+    ));
+  }
+  if let Some(base) = ttbr1_base {
+    if base & (config.tg1.page_size() as u64 - 1) != 0 {
+      return Err(MmuError::unaligned(base as usize, config.tg1.page_size()));
+    }
+  }
+
+  // an unsupported exception level is only rejected by disable_for_setup
+  disable_for_setup(el)?;
+  trace::set_panic_uart(config.panic_uart_base);
+  trace::set_peripheral_base(config.peripheral_base);
+  match el {
+    1 => el1::enable_mmu(ttbr0_base, ttbr1_base.unwrap_or(0), config),
+    2 => el2::enable_mmu(ttbr0_base, config),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+  trace::marker("mmu: enabled\r\n");
+
+  APPLIED_CONFIG = None;
+  Ok(())
+}
+
+/// Write back any dirty data and disable the MMU of the current exception level before it's configuration is changed
+///
+/// # Safety
+/// After this call any atomic operation will hang the core until the MMU is enabled again.
+///
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2
+unsafe fn disable_for_setup(el: u64) -> Result<(), MmuError> {
+  // write back any dirty data before the caches are switched off together with the MMU
+  let dcache_enabled = match el {
+    1 => el1::is_dcache_enabled(),
+    2 => el2::is_dcache_enabled(),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  };
+  if dcache_enabled {
+    cache::clean_invalidate_data_cache();
+  }
 
   // disable MMU before changing any settings and re-activating
   match el {
     1 => el1::disable_mmu(),
     2 => el2::disable_mmu(),
-    _ => unimplemented!(),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
   }
+  trace::marker("mmu: disabled\r\n");
+  Ok(())
+}
 
-  // setup translation table entries
-  let ttlb0_base_addr = ttbr0::setup_translation_tables(core, vc_mem_start, vc_mem_size) as u64;
+/// Reset the MMU to a clean disabled state. The whole data cache is cleaned, the data and instruction caches are
+/// disabled and the MMU is switched off with all TLB entries flushed. This is the inverse of ``initialize`` and leaves
+/// the system in a known state before a soft reboot or chainloading another kernel image without stale cached
+/// translations.
+///
+/// # Safety
+/// This is safe to be called on each core individually once no other code relies on the memory mapping any longer.
+/// After this call any atomic operation will hang the core until the MMU is initialized again.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2
+pub unsafe fn teardown() -> Result<(), MmuError> {
+  let el = currentel::read(currentel::EL::Field).value();
+
+  // write back any dirty data before the caches are switched off
+  cache::clean_invalidate_data_cache();
   match el {
-    1 => {
-      let ttlb1_base_addr = ttbr1::setup_translation_tables(core) as u64;
-      el1::enable_mmu(ttlb0_base_addr, ttlb1_base_addr);
-    }
-    2 => el2::enable_mmu(ttlb0_base_addr),
-    _ => unimplemented!(),
+    1 => el1::disable_caches(),
+    2 => el2::disable_caches(),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
   }
+  cache::invalidate_instruction_cache();
+
+  // disabling the MMU also flushes all TLB entries
+  match el {
+    1 => el1::disable_mmu(),
+    2 => el2::disable_mmu(),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+  aarch64_asm!(
+    "dsb   sy
+     isb"
+  );
+  Ok(())
 }
 
-/// Map a given address to a virtual address with the specified memory attributes.
-/// TODO: Memory attributes shall be a specific allowed set only - create a new type for this!
+/// Tear down the MMU like ``teardown`` does and, on core 0, forget and clear all translation tables. This hands over
+/// the system with the MMU off and zeroed translation tables, for example to another payload taking control. The data
+/// cache is cleaned before it is disabled, so no dirty data is lost.
 ///
 /// # Safety
-/// This is safe if the MMU has been configured already. Also the given raw pointer need to point to an
-/// address provided from a call to `alloc::alloc(...)` with at least `size` bytes and is aligned to the actual
-/// page size boundries.
+/// This need to be called on each core once no other code relies on the memory mapping any longer, with core 0 being
+/// the last one. After this call any atomic operation will hang the core until the MMU is initialized again.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2
+pub unsafe fn deinitialize(core: u32) -> Result<(), MmuError> {
+  teardown()?;
+
+  // the translation tables are shared by all cores, so they are cleared only once
+  if core == 0 {
+    ttbr0::reset();
+    ttbr1::reset();
+    tables::reset_pool();
+    APPLIED_CONFIG = None;
+    TABLES_INITIALIZED = false;
+
+    // the tables have been cleared with the caches disabled, so no cache line shall hold a stale copy of them
+    cache::clean_invalidate_data_cache();
+    tlb::invalidate_all();
+    aarch64_asm!(
+      "dsb   sy
+       isb"
+    );
+  }
+  Ok(())
+}
+
+/// The MMU register state of an exception level captured with ``save_state``
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MmuState {
+  /// The exception level the state has been captured in
+  pub(crate) el: u64,
+  /// TTBR0_ELx
+  pub(crate) ttbr0: u64,
+  /// TTBR1_EL1, ``0`` in EL2
+  pub(crate) ttbr1: u64,
+  /// TCR_ELx
+  pub(crate) tcr: u64,
+  /// MAIR_ELx
+  pub(crate) mair: u64,
+  /// SCTLR_ELx
+  pub(crate) sctlr: u64,
+}
+
+/// Capture the MMU register state - the translation table base, translation control, memory attribute indirection and
+/// system control registers - of the current exception level, for example before the core is powered down.
+///
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2
+pub fn save_state() -> Result<MmuState, MmuError> {
+  let el = currentel::read(currentel::EL::Field).value();
+  match el {
+    1 => Ok(el1::save_state()),
+    2 => Ok(el2::save_state()),
+    _ => Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+}
+
+/// Restore the MMU register state captured with ``save_state``, for example after the core has been woken up again.
+/// The registers are reprogrammed with the MMU disabled and all cached translations flushed before the MMU is enabled
+/// with the restored system control register.
+///
+/// # Safety
+/// This need to be called in the exception level the state has been captured in. The translation tables the state
+/// refers to need to be preserved in memory while the core has been powered down.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if the state has not been captured in EL1 or EL2
+pub unsafe fn restore_state(state: &MmuState) -> Result<(), MmuError> {
+  match state.el {
+    1 => el1::restore_state(state),
+    2 => el2::restore_state(state),
+    el => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+  Ok(())
+}
+
+/// Enable or disable privileged-access-never (PAN) by setting ``PSTATE.PAN``. While PAN is enabled any data access
+/// from EL1 to memory that is accessible from EL0 - mapped with ``AP::RW_EL1_EL0`` or ``AP::RO_EL1_EL0`` - faults.
+/// This requires the ARMv8.1-PAN extension, see ``MmuFeatures::pan``. In EL2 this is only effective with the
+/// virtualization host extension enabled.
+pub fn set_pan(enable: bool) {
+  // the instruction is given by it's encoding as the assembler may not be aware of the ARMv8.1 extensions
+  if enable {
+    unsafe { aarch64_asm!(".inst 0xd500419f // msr pan, #1"::::"volatile") };
+  } else {
+    unsafe { aarch64_asm!(".inst 0xd500409f // msr pan, #0"::::"volatile") };
+  }
+  unsafe { aarch64_asm!("isb") };
+}
+
+/// Temporarily allow EL1 to write to the EL0 page containing ``va``, for example to copy the arguments or results of a
+/// system call. The read-only bit of the access permissions of the page (AP\[2\]) is cleared before the closure is
+/// called with ``va`` and the original access permissions are restored afterwards. Both changes follow the
+/// break-before-make sequence with the required TLB maintenance. A block covering the page is split into pages first.
+///
+/// # Safety
+/// The page shall not be accessed from EL0 while the closure runs. The access permissions can not grant write access
+/// to EL1 only for memory that is accessible from EL0, so EL0 is able to write a read-only page during this time as
+/// well. PAN is not changed, the closure need to disable it if the page shall be accessed with it enabled.
+/// # Panics
+/// If the translation tables of the address region of the page are not set up, the page is not mapped or there is no
+/// translation table memory left to split the block containing it.
+pub unsafe fn with_user_page_writable<R>(va: *mut u8, f: impl FnOnce(*mut u8) -> R) -> R {
+  // the page is located either in the lower or the upper address region
+  let config = tables::region_config(va as usize);
+  let page_size = config.granule.page_size();
+  let page = va as usize & !(page_size - 1);
+
+  // without translation tables there is no entry to look up, the page is not mapped
+  if config.root.is_null() {
+    trace::fail("unable to change the access permissions of the user page");
+  }
+  let mask = TTLB_BLOCKPAGE::AP::with_value(0b11).raw_value();
+  let entry = tables::lookup(config, page)
+    .unwrap_or_else(|| trace::fail("unable to change the access permissions of the user page"));
+  let original = tables::read_entry(entry.table, entry.index) & mask;
+  let writable = original & !TTLB_BLOCKPAGE::AP::with_value(0b10).raw_value();
+  let protect = |attributes: u64| {
+    tables::protect_range(config, page, page_size, attributes, mask)
+      .unwrap_or_else(|| trace::fail("unable to change the access permissions of the user page"));
+    let pa = tables::translate(config, page).map_or(0, |(pa, _)| pa);
+    hook::notify(MapOp::Protect, pa, page, page_size, attributes);
+  };
+
+  protect(writable);
+  let result = f(va);
+  protect(original);
+  result
+}
+
+/// Change the translation tables while the MMU stays active. The closure receives a ``ReconfigBuilder`` to add
+/// mappings or change the memory attributes of existing ones. Each change follows the break-before-make sequence
+/// with the required TLB maintenance and ``SCTLR_ELx.M`` is never touched, so atomic operations keep working while
+/// the mappings are changed.
+///
+/// Changes of the translation regime itself - the size of the address regions (TxSZ), the translation granules or the
+/// translation table base addresses - can not be done while the MMU is active. They require ``deinitialize`` on all
+/// cores followed by ``initialize_with`` with the new configuration.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and no other core maintains the translation tables at the same
+/// time.
+pub unsafe fn reconfigure(f: impl FnOnce(&mut ReconfigBuilder)) {
+  f(&mut ReconfigBuilder::new());
+}
+
+/// Change the memory attributes of all "normal" memory of the 1:1 mapping of the lower address region at once, for
+/// example to switch the RAM to write-through cacheable memory for a low power mode. The VideoCore and the device
+/// memory as well as the blocks containing the translation tables keep their attributes. All affected block and page
+/// entries are re-maintained following the break-before-make sequence with a single flush of all cached translations.
+/// The data cache is cleaned before and after the change, so no dirty data gets lost and no cache line of the previous
+/// attributes is left.
+///
+/// # Safety
+/// While the entries are re-maintained any access to the "normal" memory faults. The code calling this function, it's
+/// stack and any data accessed meanwhile need to be located outside the 1:1 mapped "normal" memory, for example in
+/// the upper address region. No other core shall access the "normal" memory meanwhile.
 /// # Hint
-/// If the MMU is not configured to use the TTBR1 virtual address mapping this call has no effect and the returned
-/// address can not being used.
-pub unsafe fn map_memory(origin: *mut u8, size: usize, attributes: u64) -> *mut u8 {
+/// If the MMU has not been initialized yet this call has no effect.
+pub unsafe fn remap_all_normal(attributes: MemoryAttributes) {
+  if let Some(config) = APPLIED_CONFIG.as_ref() {
+    cache::clean_invalidate_data_cache();
+    ttbr0::remap_normal(config, attributes);
+    cache::clean_invalidate_data_cache();
+  }
+}
+
+/// Compare the live translation tables with the shadow copy maintained while ``MmuInitConfig::shadow_tables`` is
+/// enabled. Any difference indicates the translation tables have been corrupted by code writing to them directly or
+/// by a hardware error. The access flag is not compared as the hardware may manage it.
+///
+/// # Errors
+/// The index of the first differing entry within the translation table memory. Without the shadow tables enabled or
+/// the ``shadow-tables`` feature this always succeeds.
+pub fn verify_shadow() -> Result<(), usize> {
+  unsafe { tables::verify_shadow() }
+}
+
+/// Provide the virtual address the physical address ``pa`` is accessible at through the 1:1 mapping of the lower
+/// (TTBR0) address region. As the mapping is 1:1 this is the physical address itself, if it is covered by a valid
+/// block or page entry. This allows to prefer the 1:1 mapping over mapping the memory to a new virtual address.
+/// Returns ``None`` if the address is not covered by the 1:1 mapping, for example as the identity range has been
+/// limited or the address is reachable through an alias mapping only.
+pub fn identity_va(pa: usize) -> Option<*mut u8> {
+  if unsafe { ttbr0::is_identity(pa) } {
+    Some(pa as *mut u8)
+  } else {
+    None
+  }
+}
+
+/// Locate the translation table entries of the 1:1 mapping of the lower (TTBR0) address region covering the physical
+/// address ``pa``, for example to correlate a fault address with it's table entry. Returns the index of the level 1
+/// entry and the index of the level 2 entry within the table the level 1 entry refers to. With a granule whose table
+/// walk starts at level 2 the level 1 index is always 0. If the address is covered by a level 1 block the level 2
+/// index is the one the address would have in a level 2 table. Returns ``None`` if the address is not mapped.
+pub fn identity_entry_index(pa: usize) -> Option<(usize, usize)> {
+  unsafe { ttbr0::entry_indices(pa) }
+}
+
+/// Remap the 1GB region of the 1:1 mapping of the lower (TTBR0) address region starting at ``pa_gb_aligned`` with a
+/// single level 1 block entry using the given memory attributes. This gives a cheap way to change the attributes of
+/// whole gigabytes of memory, for example to partition the RAM of boards with more than 1GB, without maintaining any
+/// level 2 table. The entry is re-maintained following the break-before-make sequence and a level 2 table that
+/// maintained the region before is released. This pairs well with ``MmuInitConfig::level1_blocks``.
+///
+/// # Safety
+/// The MMU need to be initialized already. While the entry is invalid any access to the 1GB region faults, so the
+/// calling code and it's stack shall not be located within this region. Any finer grained mapping of the region, like
+/// the peripherals, is replaced by the block.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if there is no 1:1 mapping of the lower address region,
+/// ``MmuError::UnsupportedBlockSize`` if the translation granule does not support 1GB blocks,
+/// ``MmuError::Unaligned`` if the address is not 1GB aligned, ``MmuError::SizeOverflow`` if the address is outside the
+/// 1:1 mapping and ``MmuError::WouldCorruptTables`` if the region contains the translation tables and the attributes
+/// do not match the translation table walk.
+pub unsafe fn remap_gb_region(
+  pa_gb_aligned: usize,
+  attributes: MemoryAttributes,
+) -> Result<(), MmuError> {
+  ttbr0::remap_gb(pa_gb_aligned, attributes)
+}
+
+/// Choose whether the TLB maintenance done after the translation tables have been changed is broadcast to all cores of
+/// the inner shareable domain or restricted to the current core. The broadcast is required as long as the cores share
+/// the translation tables, which is the default setup. Restricting the maintenance to the current core is only safe
+/// if no other core uses the changed translation tables.
+pub fn set_tlb_broadcast(inner_shareable: bool) {
+  tlb::set_broadcast(inner_shareable);
+}
+
+/// Tell the number of cores that are currently active and share the translation tables. With a single active core, as
+/// during the early boot, the TLB maintenance is restricted to the current core which avoids the overhead of the
+/// broadcast. Once further cores come online the maintenance is broadcast to all cores of the inner shareable domain
+/// again (see ``set_tlb_broadcast``).
+pub fn set_active_cores(n: u32) {
+  tlb::set_broadcast(n > 1);
+}
+
+/// Choose whether the TLB maintenance of removed mappings is deferred. While deferred, ``unmap_memory`` and any other
+/// operation removing mappings only record the virtual addresses that need to be invalidated and the caller issues
+/// the invalidation once for a whole batch of changes with ``flush_pending``. Changed mappings are always invalidated
+/// immediately and any new mapping flushes the pending invalidations first. A removed virtual address shall not be
+/// accessed before the pending invalidation has been flushed.
+pub fn set_deferred_tlb_flush(deferred: bool) {
+  tlb::set_deferred(deferred);
+}
+
+/// Invalidate the cached translations of all mappings removed since the last flush while the TLB maintenance is
+/// deferred. If more mappings have been removed than could be recorded all cached translations are invalidated.
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn flush_pending() {
+  tlb::flush_pending();
+}
+
+/// Switch the lower (TTBR0) address region to the translation tables at ``new_base`` at once. This allows to build a
+/// new set of translation tables for sweeping changes and activate it afterwards, instead of re-maintaining the
+/// active entries one by one following the break-before-make sequence. The new base is written to the TTBR0_ELx
+/// register followed by an ``isb`` and all cached translations are flushed, as the entries of the previous tables may
+/// still be cached. Any further translation table maintenance of the lower address region is done on the new tables.
+///
+/// # Safety
+/// The new translation tables need to be fully constructed with the granule and address range size the lower address
+/// region is configured with, and their memory need to be coherent with the table walk - for example written with the
+/// cacheable 1:1 mapping followed by a ``dsb``. They need to cover the code, the stack and any data accessed
+/// afterwards. No other core shall maintain the translation tables meanwhile.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1 or EL2
+pub unsafe fn swap_ttbr0(new_base: u64) -> Result<(), MmuError> {
+  aarch64_asm!("dsb   ishst");
+  let el = currentel::read(currentel::EL::Field).value();
+  match el {
+    1 => el1::set_ttbr0(new_base),
+    2 => el2::set_ttbr0(new_base),
+    _ => return Err(MmuError::UnsupportedExceptionLevel(el as u8)),
+  }
+  ttbr0::set_root(new_base as *mut u64);
+  Ok(())
+}
+
+/// Change the size of the upper (TTBR1) virtual address range to 2^(64-T1SZ) bytes. The translation tables of the
+/// upper address region are rebuilt for the new size, all existing mappings are migrated and the TCR_EL1.T1SZ and
+/// TTBR1_EL1 registers are updated with all cached translations flushed. A smaller address range may require less
+/// table levels to be walked. The default T1SZ is 25, giving a 512GB address range.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already, no other core maintains the translation tables at the same
+/// time and no code is running from the upper address region.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL1, ``MmuError::InvalidWindowSize`` if T1SZ is out of
+/// the supported range, ``MmuError::MappingOutsideWindow`` if any existing mapping is not covered by the new address
+/// range and ``MmuError::TableMemoryExhausted`` if there is no memory left for the new translation tables.
+pub unsafe fn resize_ttbr1_window(t1sz: u8) -> Result<(), MmuError> {
+  let el = currentel::read(currentel::EL::Field).value();
+  if el != 1 {
+    return Err(MmuError::UnsupportedExceptionLevel(el as u8));
+  }
+  // the address range is at most 48 bits wide
+  if !(16..=39).contains(&t1sz) {
+    return Err(MmuError::InvalidWindowSize);
+  }
+
+  let previous = ttbr1::resize_window(64 - t1sz as usize)?;
+  el1::set_ttbr1_window(ttbr1::mmu_config().root as u64, t1sz as u64);
+  // the previous tables are no longer used once the cached translations are flushed
+  tables::release_tables(&previous);
+  Ok(())
+}
+
+/// Map a given address to a virtual address with the specified memory attributes. The attributes are the raw
+/// translation table entry bits, use ``MemoryAttributes`` to build a valid set and pass it's ``raw`` value.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already. Also the given raw pointer need to point to an
+/// address provided from a call to `alloc::alloc(...)` with at least `size` bytes.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if the MMU does not provide the upper (TTBR1) address region in the current
+/// exception level or it is not configured, ``MmuError::Unaligned`` if ``origin`` is not aligned to the page size,
+/// ``MmuError::RequestTooLarge`` if the memory spans more level 2 blocks than configured with
+/// ``MmuInitConfig::max_blocks_per_map``, ``MmuError::AddressSpaceExhausted`` if there is no free virtual address
+/// block left, ``MmuError::InvalidPhysicalRegion`` if the memory of the peripherals or the VideoCore is mapped with
+/// cacheable memory attributes and ``MmuError::WouldCorruptTables`` if the memory of the translation tables is mapped
+/// with other attributes than the translation table walk is configured with.
+pub unsafe fn map_memory(
+  origin: *mut u8,
+  size: usize,
+  attributes: u64,
+) -> Result<*mut u8, MmuError> {
+  if currentel::read(currentel::EL::Field).value() != 1 {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  if origin as usize & (page_size() - 1) != 0 {
+    return Err(MmuError::unaligned(origin as usize, page_size()));
+  }
+  try_map_memory(origin, size, attributes, 1)
+}
+
+/// Map a given address to a virtual address aligned to ``align`` bytes with the specified memory attributes like
+/// ``map_memory`` does. The address does not need to be page aligned and without the upper (TTBR1) address region
+/// in the current exception level the address itself is provided if it is aligned as requested.
+unsafe fn try_map_memory(
+  origin: *mut u8,
+  size: usize,
+  attributes: u64,
+  align: usize,
+) -> Result<*mut u8, MmuError> {
   // the mmu configuration depends on the exception level we are running in
   let el = currentel::read(currentel::EL::Field).value();
+  check_physical_region(
+    origin as usize,
+    size,
+    MemoryAttributes::from_raw(attributes),
+  )?;
   if el == 1 {
-    ttbr1::maintain_pages(origin, size, attributes)
+    ttbr1::maintain_pages(origin, size, attributes, align)
+  } else if origin as usize & (align - 1) != 0 {
+    Err(MmuError::AlignmentUnsatisfiable)
+  } else {
+    Ok(origin)
+  }
+}
+
+/// Check whether the physical memory region may be mapped with the given memory attributes
+///
+/// # Errors
+/// ``MmuError::InvalidPhysicalRegion`` if the region covers the peripherals or the VideoCore memory and the attributes
+/// are cacheable and ``MmuError::WouldCorruptTables`` if the region covers the memory of the translation tables and the
+/// attributes are not the ones the translation table walk is configured with
+fn check_physical_region(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<(), MmuError> {
+  // peripherals and VideoCore memory are never accessed through the caches, so a cacheable alias is a bug
+  let cacheable = matches!(attributes.mair_index(), 4 | 6 | 7);
+  if cacheable && ttbr0::overlaps_device_or_vc(current_config(), pa, size) {
+    return Err(MmuError::InvalidPhysicalRegion);
+  }
+  // accessing the translation tables with a different memory type would break the translation table walk
+  if tables::overlaps_table_memory(pa, size) && !attributes.is_table_walk_compatible() {
+    return Err(MmuError::WouldCorruptTables);
+  }
+  Ok(())
+}
+
+/// Map a physical memory region to a new virtual address with the specified memory attributes while running in EL2.
+/// As EL2 does not provide an upper (TTBR1) address region, the alias is placed in the upper half of the lower
+/// address region which is never covered by the 1:1 mapping. This allows for example a non-cacheable view of
+/// cacheable RAM. The physical address does not need to be aligned, the returned virtual address has the same offset
+/// into the page.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// ``MmuError::UnsupportedExceptionLevel`` if not running in EL2, ``MmuError::AddressSpaceExhausted`` if the virtual
+/// address range for aliases is used up and ``MmuError::TableMemoryExhausted`` if there is no memory left for the
+/// required translation tables.
+pub unsafe fn map_alias_el2(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<*mut u8, MmuError> {
+  let el = currentel::read(currentel::EL::Field).value();
+  if el != 2 {
+    return Err(MmuError::UnsupportedExceptionLevel(el as u8));
+  }
+
+  ttbr0::map_alias(pa, size, attributes)
+}
+
+/// Map a huge physical memory region, spanning gigabytes, to a new virtual address of the upper (TTBR1) address region
+/// with the specified memory attributes. The 1GB aligned parts of the region are mapped with level 1 block entries,
+/// only the remainder is mapped with 2MB blocks and pages. This requires far less translation table entries and
+/// cached translations than ``map_memory``. The physical address does not need to be aligned, the returned virtual
+/// address has the same offset into the page. The mapping is removed with ``unmap_memory``.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if not running in EL1, ``MmuError::UnsupportedBlockSize`` if the translation
+/// granule of the upper address region does not support 1GB blocks, ``MmuError::SizeOverflow`` if the size exceeds the
+/// address range, ``MmuError::InvalidPhysicalRegion`` and ``MmuError::WouldCorruptTables`` under the same conditions
+/// as for ``map_memory``, ``MmuError::AddressSpaceExhausted`` if there is no free 1GB aligned virtual address range
+/// left that is large enough and ``MmuError::TableMemoryExhausted`` if there is no memory left for the translation
+/// tables.
+pub unsafe fn map_huge(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<*mut u8, MmuError> {
+  if currentel::read(currentel::EL::Field).value() != 1 {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  check_physical_region(pa, size, attributes)?;
+  ttbr1::map_huge(pa, size, attributes)
+}
+
+/// Unmap the virtual address provided by a previous call to ``map_memory`` or ``map_huge``. The translation is removed
+/// and any cached translation is flushed, so any further access to this virtual address faults.
+///
+/// # Safety
+/// This is safe if the virtual address has been provided by ``map_memory`` or ``map_huge`` and is not accessed any
+/// longer.
+/// # Hint
+/// If the MMU is not configured to use the TTBR1 virtual address mapping this call has no effect.
+pub unsafe fn unmap_memory(va: *mut u8, size: usize) {
+  if currentel::read(currentel::EL::Field).value() == 1 {
+    ttbr1::release_pages(va, size);
+  }
+}
+
+/// A virtual memory mapping provided by ``map_guarded`` that is unmapped once it is dropped. The guard records the
+/// whole extent of the mapping, so all blocks and pages it spans are released at once.
+#[derive(Debug)]
+pub struct MappedRegion {
+  va: *mut u8,
+  size: usize,
+}
+
+impl MappedRegion {
+  /// The virtual address the memory is accessible at
+  pub fn va(&self) -> *mut u8 {
+    self.va
+  }
+
+  /// The size of the mapped memory
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  /// Keep the mapping beyond the lifetime of the guard and provide it's virtual address. The mapping needs to be
+  /// released with ``unmap_memory`` afterwards.
+  pub fn into_raw(self) -> *mut u8 {
+    let va = self.va;
+    core::mem::forget(self);
+    va
+  }
+}
+
+impl Drop for MappedRegion {
+  fn drop(&mut self) {
+    unsafe { unmap_memory(self.va, self.size) };
+  }
+}
+
+/// Map the memory at the given address to a virtual address with the specified memory attributes like ``map_memory``
+/// does and provide a guard that unmaps the memory once it is dropped.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the memory at ``origin`` is valid for the whole size given.
+/// The virtual address shall not be used any longer once the guard has been dropped.
+/// # Errors
+/// ``MmuError::RequestTooLarge`` if the memory spans more level 2 blocks than configured with
+/// ``MmuInitConfig::max_blocks_per_map``.
+pub unsafe fn map_guarded(
+  origin: *mut u8,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<MappedRegion, MmuError> {
+  let va = try_map_memory(origin, size, attributes.raw(), 1)?;
+  Ok(MappedRegion { va, size })
+}
+
+/// Map the memory at the given address to a virtual address with the specified memory attributes and provide a
+/// typed slice of ``count`` elements over the mapped memory. The mapping covers the memory occupied by the elements
+/// rounded up to the next page boundary. As the mapping persists until it is explicitly unmapped the slice has a
+/// ``'static`` lifetime.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the memory at ``origin`` is valid for ``count`` elements
+/// of type ``T`` that are not accessed through any other reference while the slice is in use.
+/// # Errors
+/// ``MmuError::ZeroSizedType`` if ``T`` is a zero sized type, ``MmuError::Unaligned`` if ``origin`` is not aligned
+/// for ``T``, ``MmuError::SizeOverflow`` if the elements would exceed the address range and
+/// ``MmuError::RequestTooLarge`` if the mapping would exceed ``MmuInitConfig::max_blocks_per_map``.
+pub unsafe fn map_slice<T>(
+  origin: *mut u8,
+  count: usize,
+  attributes: MemoryAttributes,
+) -> Result<&'static mut [T], MmuError> {
+  if size_of::<T>() == 0 {
+    return Err(MmuError::ZeroSizedType);
+  }
+  if origin as usize % align_of::<T>() != 0 {
+    return Err(MmuError::unaligned(origin as usize, align_of::<T>()));
+  }
+  let size = count
+    .checked_mul(size_of::<T>())
+    .ok_or(MmuError::SizeOverflow)?;
+
+  let va = try_map_memory(origin, page_align(size), attributes.raw(), 1)?;
+  Ok(slice::from_raw_parts_mut(va as *mut T, count))
+}
+
+/// Map a physical memory region to a virtual address with non-global memory attributes belonging to the given ASID.
+/// The translations of non-global entries are cached in the TLB tagged with the ASID that is active (TTBR0_EL1.ASID)
+/// while the memory is accessed. So as long as the memory is only accessed while ``asid`` is active, a later call to
+/// ``flush_asid(asid)`` invalidates all cached translations of this mapping - together with any other non-global
+/// translation cached for this ASID.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if not running in EL1, ``MmuError::InvalidAsid`` if the ASID exceeds 8 bits,
+/// ``MmuError::InvalidPhysicalRegion`` and ``MmuError::WouldCorruptTables`` under the same conditions as for
+/// ``map_memory`` and ``MmuError::RequestTooLarge`` if the mapping would exceed ``MmuInitConfig::max_blocks_per_map``.
+pub unsafe fn map_for_asid(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+  asid: u16,
+) -> Result<*mut u8, MmuError> {
+  if currentel::read(currentel::EL::Field).value() != 1 {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  // the MMU is configured to use 8 bit ASIDs
+  if asid > 0xFF {
+    return Err(MmuError::InvalidAsid);
+  }
+  check_physical_region(pa, size, attributes)?;
+
+  let attributes = attributes.raw() | TTLB_BLOCKPAGE::NG::SET.raw_value();
+  ttbr1::maintain_pages(pa as *mut u8, size, attributes, 1)
+}
+
+/// Invalidate all cached translations of non-global mappings tagged with the given ASID on all cores. This covers
+/// the mappings created with ``map_for_asid`` that have been accessed while this ASID was active.
+///
+/// # Safety
+/// This is always safe to be called in EL1, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn flush_asid(asid: u16) {
+  tlb::invalidate_asid(asid);
+}
+
+/// Map the physical memory region used for a DMA transfer as non-cacheable memory, run the given closure with the
+/// mapped virtual address and unmap the memory again. The data cache lines of the region are cleaned and invalidated
+/// before the closure runs, so no dirty cacheable data can overwrite the buffer, and again afterwards, so no line
+/// fetched speculatively through the cacheable 1:1 mapping while the device was accessing the memory hides the
+/// result. Within the closure the CPU prepares the buffer, triggers the device accessing the physical address and
+/// reads the result without any further cache maintenance.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given. The virtual address shall not be used after the closure has returned.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if not running in EL1 and ``MmuError::RequestTooLarge`` if the mapping would exceed
+/// ``MmuInitConfig::max_blocks_per_map``.
+pub unsafe fn with_dma_buffer<R>(
+  pa: usize,
+  size: usize,
+  f: impl FnOnce(*mut u8) -> R,
+) -> Result<R, MmuError> {
+  if currentel::read(currentel::EL::Field).value() != 1 {
+    return Err(MmuError::NoVirtualMapping);
+  }
+
+  // the cache maintenance is done through the cacheable 1:1 mapping of the region if there is any
+  let identity = ttbr0::is_identity_mapped(pa, size, MemoryAttributes::normal_cached());
+  if identity {
+    cache::clean_invalidate_range(pa, size);
+  }
+  let va = try_map_memory(
+    pa as *mut u8,
+    size,
+    MemoryAttributes::normal_uncached().raw(),
+    1,
+  )?;
+  let result = f(va);
+  if identity {
+    cache::clean_invalidate_range(pa, size);
+  }
+  unmap_memory(va, size);
+
+  Ok(result)
+}
+
+/// The changes to the translation tables a mapping would do, as determined by ``plan_map``
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapPlan {
+  /// The virtual address the memory would be accessible at
+  pub va: usize,
+  /// The indices of the level 2 entries of the upper (TTBR1) address region that would be used, either as block entries
+  /// or with the page entries of a level 3 table
+  pub block_entries: Range<usize>,
+  /// The number of level 3 page entries that would be written. A mapping only uses level 2 entries that are unused or
+  /// refer to a page table already, so no block is ever split into pages.
+  pub page_entries: usize,
+  /// The translation table memory in bytes that would be allocated
+  pub table_memory: usize,
+}
+
+/// Determine the changes to the translation tables mapping the physical memory region with ``map_memory`` would do,
+/// without actually changing them. This allows to validate a mapping before it is done. If the MMU does not provide a
+/// virtual address mapping in the current exception level the plan contains the physical address and no changes.
+///
+/// # Errors
+/// ``MmuError::InvalidPhysicalRegion`` and ``MmuError::WouldCorruptTables`` if ``map_memory`` would refuse to map the
+/// region with the given attributes, ``MmuError::RequestTooLarge`` if the mapping would exceed
+/// ``MmuInitConfig::max_blocks_per_map``, ``MmuError::AddressSpaceExhausted`` if there is no free virtual address block
+/// left and ``MmuError::NoVirtualMapping`` if the translation tables are set up with the first mapping
+/// (``MmuInitConfig::lazy_ttbr1``) which has not happened yet.
+pub fn plan_map(pa: usize, size: usize, attributes: MemoryAttributes) -> Result<MapPlan, MmuError> {
+  check_physical_region(pa, size, attributes)?;
+  if currentel::read(currentel::EL::Field).value() == 1 {
+    unsafe { ttbr1::plan_pages(pa as *mut u8, size, 1) }
+  } else {
+    Ok(MapPlan {
+      va: pa,
+      block_entries: 0..0,
+      page_entries: 0,
+      table_memory: 0,
+    })
+  }
+}
+
+/// The result of mapping a physical memory region
+#[derive(Copy, Clone, Debug)]
+pub struct PhysicalMapping {
+  /// The virtual address the physical memory region is accessible at
+  pub va: *mut u8,
+  /// The physical memory region is already covered by the 1:1 mapping of the lower address region with matching
+  /// memory attributes, so the virtual address equals the physical one. Such a mapping shall never be unmapped.
+  pub reused_identity: bool,
+}
+
+/// Map a physical memory region with the specified memory attributes to a virtual address aligned to ``align`` bytes.
+/// If the region is already covered by the 1:1 mapping of the lower address region with exactly the requested
+/// attributes and the physical address is aligned as requested, this mapping is reused and no virtual address block is
+/// occupied. Otherwise the region is mapped to a new virtual address like ``map_memory`` does. As the mapping keeps
+/// the offset of the physical address into a level 2 block, an alignment up to the block size need to be given by
+/// the physical address already, while a larger alignment is achieved by choosing a suitably aligned virtual block.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// ``MmuError::WouldCorruptTables`` if the region covers the memory of the translation tables and the attributes are
+/// not the write-back cacheable, inner shareable ones the translation table walk is configured with,
+/// ``MmuError::RequestTooLarge`` if the mapping would exceed ``MmuInitConfig::max_blocks_per_map``,
+/// ``MmuError::AddressSpaceExhausted`` if there is no free virtual address block left,
+/// ``MmuError::InvalidPhysicalRegion`` if the region covers the peripherals or the VideoCore memory and the attributes
+/// are cacheable and ``MmuError::AlignmentUnsatisfiable`` if ``align`` is not a power of two of at least the page size
+/// or there is no free virtual address range with the requested alignment.
+pub unsafe fn map_physical(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+  align: usize,
+) -> Result<PhysicalMapping, MmuError> {
+  if !align.is_power_of_two() || align < page_size() {
+    return Err(MmuError::AlignmentUnsatisfiable);
+  }
+  check_physical_region(pa, size, attributes)?;
+
+  if pa & (align - 1) == 0 && ttbr0::is_identity_mapped(pa, size, attributes) {
+    Ok(PhysicalMapping {
+      va: pa as *mut u8,
+      reused_identity: true,
+    })
+  } else {
+    Ok(PhysicalMapping {
+      va: try_map_memory(pa as *mut u8, size, attributes.raw(), align)?,
+      reused_identity: false,
+    })
+  }
+}
+
+/// Map a device memory region with the given ordering constraints like ``map_physical`` does. The memory is mapped as
+/// never executable device memory using the MAIR slot matching the ordering. A peripheral with a strictly ordered
+/// register block and a relaxed ordered data region, like a framebuffer, is mapped with one call for each of them.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid device memory for the
+/// whole size given.
+/// # Errors
+/// The errors of ``map_physical``
+pub unsafe fn map_device_region(
+  pa: usize,
+  size: usize,
+  ordering: DeviceOrdering,
+) -> Result<*mut u8, MmuError> {
+  map_physical(
+    pa,
+    size,
+    MemoryAttributes::device_ordered(ordering),
+    page_size(),
+  )
+  .map(|mapping| mapping.va)
+}
+
+/// Map the buffer of the VideoCore mailbox property interface like ``map_physical`` does. The buffer is mapped as
+/// non-cacheable, outer shareable "normal" memory (see ``MemoryAttributes::mailbox_buffer``), so the ARM cores and the
+/// VideoCore always see the same content and the responses written by the VideoCore are never read stale from the
+/// caches.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// The same as ``map_physical``.
+pub unsafe fn map_mailbox_buffer(pa: usize, size: usize) -> Result<*mut u8, MmuError> {
+  map_physical(pa, size, MemoryAttributes::mailbox_buffer(), page_size()).map(|mapping| mapping.va)
+}
+
+/// Map a buffer accessed by a DMA engine like ``map_physical`` does. The buffer of a ``coherent`` DMA engine is mapped
+/// as write-back cacheable, outer shareable "normal" memory, the buffer of any other DMA engine as non-cacheable,
+/// outer shareable "normal" memory (see ``MemoryAttributes::dma_buffer``).
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the physical memory region is valid for the whole size
+/// given.
+/// # Errors
+/// The same as ``map_physical``.
+pub unsafe fn map_for_dma(pa: usize, size: usize, coherent: bool) -> Result<*mut u8, MmuError> {
+  map_physical(
+    pa,
+    size,
+    MemoryAttributes::dma_buffer(coherent),
+    page_size(),
+  )
+  .map(|mapping| mapping.va)
+}
+
+/// Ensure the page containing the physical address ``pa`` is 1:1 mapped as executable "normal" memory in the lower
+/// (TTBR0) address region, so a secondary core can run a startup trampoline from it before and after it enables it's
+/// MMU. If the page is covered by a block with different attributes, the block is split and only this page gets the
+/// "normal" memory attributes. The data cache lines of the page are cleaned afterwards, as the secondary core fetches
+/// the trampoline with it's MMU and caches disabled.
+///
+/// # Safety
+/// The MMU need to be initialized already. While a block is split it is temporarily invalid, so the calling code and
+/// it's stack shall not be located in the same block.
+/// # Errors
+/// ``MmuError::NoVirtualMapping`` if there is no 1:1 mapping of the lower address region and
+/// ``MmuError::TableMemoryExhausted`` if there is no memory left to split the block containing the page.
+pub unsafe fn reserve_trampoline(pa: usize) -> Result<(), MmuError> {
+  let config = ttbr0::mmu_config();
+  if config.root.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+
+  let page_size = config.granule.page_size();
+  let page = pa & !(page_size - 1);
+  let attributes = MemoryAttributes::normal_cached();
+  if !ttbr0::is_identity_mapped(page, page_size, attributes) {
+    ReconfigBuilder::new().map(page, page, page_size, attributes)?;
+  }
+  cache::clean_invalidate_range(page, page_size);
+  Ok(())
+}
+
+/// Protect the exception vector table the VBAR_ELx register points to. The page containing the 2kB vector table is
+/// re-maintained as read-only memory executable from the privileged exception level only, so the vectors remain
+/// executable but can never be written at runtime. If the vector table is covered by a block entry this block is split into pages first.
+///
+/// # Safety
+/// The MMU need to be initialized already. While the entry covering the vector table is changed it is temporarily
+/// invalid, so this shall not be called while executing code or taking exceptions within the same block.
+/// # Panics
+/// If the vector table is not mapped or there is no translation table memory left to split the block containing it.
+pub unsafe fn protect_vectors(vbar: usize) {
+  // the vector table is located either in the lower or the upper address region
+  let config = tables::region_config(vbar);
+
+  // replacing the access permissions and clearing the privileged execute never flag keeps the vectors executable.
+  // In EL1 the XN flag is the unprivileged execute never flag, which stays set so the vectors and any code sharing
+  // their page are not executable from EL0. The EL2 translation regime only provides the XN flag.
+  let mask = (TTLB_BLOCKPAGE::AP::with_value(0b11)
+    | TTLB_BLOCKPAGE::PXN::with_value(1)
+    | TTLB_BLOCKPAGE::XN::with_value(1))
+  .raw_value();
+  let execute_never = if currentel::read(currentel::EL::Field).value() == 1 {
+    TTLB_BLOCKPAGE::XN::with_value(1).raw_value()
   } else {
-    origin
+    0
+  };
+  let attributes = TTLB_BLOCKPAGE::AP::RO_EL1.raw_value() | execute_never;
+  tables::protect_range(config, vbar, 0x800, attributes, mask)
+    .unwrap_or_else(|| trace::fail("unable to protect the exception vector table"));
+  let pa = tables::translate(config, vbar).map_or(0, |(pa, _)| pa);
+  hook::notify(MapOp::Protect, pa, vbar, 0x800, attributes);
+  // the vectors might have been written right before they are protected
+  cache::sync_instruction_cache(vbar as *const u8, 0x800);
+}
+
+/// Promote the pages covering the block of the virtual address ``block_va`` back into a single block entry. This is
+/// only possible if all entries of the level 3 table backing the block are contiguous pages with uniform memory
+/// attributes. The level 2 entry is replaced following the break-before-make sequence and the level 3 table is
+/// released. This is the counterpart to splitting a block into pages and reduces the number of cached translations.
+/// Returns ``false`` if the block could not be promoted.
+///
+/// # Safety
+/// This is safe if the MMU has been configured already and the memory covered by the block is not accessed while it
+/// is re-maintained.
+pub unsafe fn try_promote_block(block_va: usize) -> bool {
+  // the block is located either in the lower or the upper address region
+  let config = tables::region_config(block_va);
+  tables::promote_block(config, block_va)
+}
+
+/// Translate the virtual address ``va`` into the physical address it is mapped to by walking the translation tables in
+/// software the same way the hardware table walk does. The address selects the lower (TTBR0) or the upper (TTBR1)
+/// address region, so an address of the 1:1 mapping translates to itself. Returns ``None`` if any entry on the walk is
+/// invalid or the address is located in the gap between both address regions.
+pub fn virt_to_phys(va: *const u8) -> Option<u64> {
+  unsafe {
+    let config = tables::region_config(va as usize);
+    tables::translate(config, va as usize).map(|(pa, _)| pa as u64)
+  }
+}
+
+/// Check whether the virtual memory range ``va..va + size`` is completely mapped. Each block or page entry covering the
+/// range need to be valid, regardless of the mix of block and page sizes. This allows to validate a buffer handed in
+/// by a caller before it is accessed as a whole. Returns ``false`` on the first part of the range that is not mapped.
+pub fn is_fully_mapped(va: *const u8, size: usize) -> bool {
+  // the range is located either in the lower or the upper address region
+  let va = va as usize;
+  let config = unsafe { tables::region_config(va) };
+
+  let end = va.saturating_add(size.max(1));
+  let mut addr = va;
+  while addr < end {
+    match unsafe { tables::translate(config, addr) } {
+      Some((_, entry_size)) => addr = (addr & !(entry_size - 1)).saturating_add(entry_size),
+      None => return false,
+    }
+  }
+
+  true
+}
+
+/// Populate the TLB with the translations of the virtual memory range ``va..va + size`` ahead of a latency critical
+/// section. Each block or page entry covering the range is touched once, so a range mapped with 2MB blocks is touched
+/// every 2MB and a range mapped with pages every page. "Normal" memory only accessible from EL1 is touched with a read
+/// of one byte. Any other memory, where a read might have side effects or fault with PAN enabled, is touched with an
+/// address translation instruction (``AT S1E1R``/``AT S1E2R``), whether it's result is cached in the TLB is
+/// implementation defined. Parts of the range that are not mapped are skipped.
+pub fn prefault(va: *const u8, size: usize) {
+  // the range is located either in the lower or the upper address region
+  let va = va as usize;
+  let config = unsafe { tables::region_config(va) };
+  if config.root.is_null() {
+    return;
+  }
+  let el = currentel::read(currentel::EL::Field).value();
+
+  let end = va.saturating_add(size.max(1));
+  let mut addr = va;
+  while addr < end {
+    let entry = match unsafe { tables::lookup(config, addr) } {
+      Some(entry) => entry,
+      // the part of the range below the virtual address range of the upper region is skipped
+      None if addr >> 63 == 1 && addr < !0 << config.va_bits => {
+        addr = !0 << config.va_bits;
+        continue;
+      }
+      None => break,
+    };
+    let value = unsafe { tables::read_entry(entry.table, entry.index) };
+    if tables::is_valid(value) {
+      let attributes = MemoryAttributes::from_raw(value);
+      let el1_only = TTLB_BLOCKPAGE::AP::from_raw(value).value() & 0b01 == 0;
+      if matches!(attributes.mair_index(), 3 | 4 | 6 | 7) && el1_only {
+        unsafe { core::ptr::read_volatile(addr as *const u8) };
+      } else if el == 1 {
+        unsafe { aarch64_asm!("at s1e1r, $0"::"r"(addr)::"volatile") };
+      } else {
+        unsafe { aarch64_asm!("at s1e2r, $0"::"r"(addr)::"volatile") };
+      }
+    }
+    let entry_size = 1usize << config.granule.level_shift(entry.level);
+    addr = (addr & !(entry_size - 1)).saturating_add(entry_size);
+  }
+}
+
+/// Check whether the virtual memory range ``va..va + size`` is mapped to one contiguous physical memory range. Each
+/// block or page covering the range is translated and the output addresses need to follow each other without any
+/// gap, regardless of the mix of block and page sizes. This allows to hand a virtual buffer to a DMA device with a
+/// single base address and length. Returns ``false`` if any part of the range is not mapped.
+pub fn is_physically_contiguous(va: *const u8, size: usize) -> bool {
+  // the range is located either in the lower or the upper address region
+  let va = va as usize;
+  let config = unsafe { tables::region_config(va) };
+
+  let end = va.saturating_add(size.max(1));
+  let mut addr = va;
+  let mut expected = None;
+  while addr < end {
+    match unsafe { tables::translate(config, addr) } {
+      Some((pa, entry_size)) if expected.unwrap_or(pa) == pa => {
+        let next = (addr & !(entry_size - 1)).saturating_add(entry_size);
+        expected = Some(pa + (next - addr));
+        addr = next;
+      }
+      _ => return false,
+    }
+  }
+
+  true
+}
+
+/// The size in bytes of the largest contiguous free virtual address range of the upper (TTBR1) address region
+/// ``map_memory`` hands out addresses from. A mapping of at most this size, including the offset of the physical
+/// address into it's first block, fits into the free address range. Returns ``0`` if not running in EL1 or the translation tables
+/// are not set up yet.
+pub fn largest_free_va_region() -> usize {
+  if currentel::read(currentel::EL::Field).value() != 1 {
+    return 0;
+  }
+  unsafe { ttbr1::largest_free_range() }
+}
+
+/// The maximum physical address that can be reached with the current MMU configuration. This is limited by the
+/// physical address size configured in TCR_ELx (IPS/PS) - 4GB with the default 32 bits - and by the physical address
+/// range covered by the root table entries of the 1:1 mapping that are set up. Returns ``0`` if the MMU is not
+/// configured yet.
+pub fn max_physical_address() -> usize {
+  let parange = match currentel::read(currentel::EL::Field).value() {
+    1 => el1::physical_address_size(),
+    2 => el2::physical_address_size(),
+    _ => return 0,
+  };
+  let pa_limit = 1usize << features::pa_bits(parange);
+  let identity_end = unsafe { ttbr0::identity_end() };
+  pa_limit.min(identity_end).saturating_sub(1)
+}
+
+/// Report the memory consumed by the translation tables of the lower (TTBR0) and the upper (TTBR1) address region.
+/// Mapping memory with pages instead of blocks requires additional next level tables, which are handed out from the
+/// statically reserved table memory.
+pub fn table_memory_usage() -> TableUsage {
+  unsafe { tables::usage(&[ttbr0::mmu_config(), ttbr1::mmu_config()]) }
+}
+
+/// Serialize the live translation tables of the lower (TTBR0) and the upper (TTBR1) address region into the given
+/// buffer for offline inspection, for example to capture the MMU state at a fault. Returns the number of bytes
+/// written. The buffer is filled with little endian 64 bit values in the following layout:
+///
+/// | Values                           | Description                                                              |
+/// |----------------------------------|--------------------------------------------------------------------------|
+/// | magic, version, tables           | the ``MMUTABLE`` magic, the layout version (1) and the number of tables  |
+/// | page shift, VA bits, start level | once for TTBR0 and TTBR1, all 0 if there are no tables for the region    |
+/// | region, level, address, base, n  | a table record of the region (0 = TTBR0, 1 = TTBR1) and level, with the  |
+/// |                                  | table address and virtual base address followed by the n raw entries     |
+///
+/// The table records are written depth first, so a table is followed by the next level tables it refers to.
+///
+/// # Errors
+/// ``MmuError::BufferTooSmall`` if the buffer can not hold all translation tables
+pub fn export_tables(buf: &mut [u8]) -> Result<usize, MmuError> {
+  unsafe {
+    let ttbr1_config = ttbr1::mmu_config();
+    tables::export(
+      &[
+        (ttbr0::mmu_config(), 0),
+        (ttbr1_config, !0 << ttbr1_config.va_bits),
+      ],
+      buf,
+    )
   }
 }
 
-/// Align a given address/size to the next page boundary based on MMU config
+/// Whether the MMU of the current exception level is enabled (SCTLR_ELx.M). As long as the MMU is not active any
+/// atomic operation hangs the core, so code relying on atomics may check this first. Returns ``false`` in any exception
+/// level other than EL1 and EL2.
+pub fn is_mmu_enabled() -> bool {
+  match currentel::read(currentel::EL::Field).value() {
+    1 => el1::is_mmu_enabled(),
+    2 => el2::is_mmu_enabled(),
+    _ => false,
+  }
+}
+
+/// Provide the configuration the MMU has been initialized with by ``initialize`` or ``initialize_with``. This allows
+/// code to take decisions consistent with the actual MMU setup, like the granule or the VideoCore memory region,
+/// without passing the configuration around. Returns ``None`` as long as the MMU has not been initialized.
+pub fn current_config() -> Option<&'static MmuInitConfig> {
+  unsafe { APPLIED_CONFIG.as_ref() }
+}
+
+/// Align a given address/size to the next boundary of a ``page_size`` page. An address within the last page of the
+/// address space saturates at the start of this page instead of wrapping around. The page size follows the granule
+/// the MMU is configured with, unlike the 4kB page ``page_align_down`` aligns to.
 pub fn page_align(addr: usize) -> usize {
-  (addr + config::PAGE_MASK) & !config::PAGE_MASK
+  let page_mask = page_size() - 1;
+  try_page_align(addr).unwrap_or(!page_mask)
 }
 
+/// Align a given address/size to the next page boundary like ``page_align`` does. Returns ``None`` if the next page
+/// boundary exceeds the address space.
+pub fn try_page_align(addr: usize) -> Option<usize> {
+  let page_mask = page_size() - 1;
+  addr.checked_add(page_mask).map(|addr| addr & !page_mask)
+}
+
+/// Align a given address down to the start of the **4kB** page it is located in. This is a ``const fn`` that does not
+/// depend on the MMU configuration, so it always aligns to the 4kB page of the smallest granule and is not the inverse
+/// of ``page_align`` if the MMU is configured with the 16kB or 64kB granule. Use ``addr & !(page_size() - 1)`` to align
+/// down to the page of the configured granule.
+pub const fn page_align_down(addr: usize) -> usize {
+  addr & !config::PAGE_MASK
+}
+
+/// The page size of the translation granule memory is mapped with by ``map_memory``. This is the granule of the upper
+/// (TTBR1) address region in EL1 and of the lower (TTBR0) address region in EL2. Falls back to 4kB as long as the MMU
+/// is not initialized.
 pub fn page_size() -> usize {
-  config::PAGE_SIZE
+  match current_config() {
+    Some(applied) if currentel::read(currentel::EL::Field).value() == 2 => applied.tg0.page_size(),
+    Some(applied) => applied.tg1.page_size(),
+    None => config::PAGE_SIZE,
+  }
 }
 
-#[repr(C, align(4096))]
+/// The configuration the MMU has been initialized with last
+static mut APPLIED_CONFIG: Option<MmuInitConfig> = None;
+
+/// Whether core 0 has set up the translation tables. They are not set up again until ``deinitialize`` cleared them.
+static mut TABLES_INITIALIZED: bool = false;
+
+/// The translation table configuration of one address region (TTBR0 or TTBR1)
+#[derive(Copy, Clone)]
 struct MmuConfig {
-  /// TLB Level 1 entries will cover a memory range of 1GB each. For a Raspberry Pi we would only need 2 entries on
-  /// this level, however, we would like to have the subsequent tables to start as 4kb aligned address, so reserving
-  /// 512 entries here
-  ttlb_lvl1: [u64; 512],
-  /// TLB Level 2 entries will cover a memory range of 2MB each, so to maintain entries for the first 1GB of the
-  /// Raspberry Pi 512 entries would be enough, however we would need to map the peripheral address space as well and
-  /// they are above the 1GB mark but not greater than 2MB, so 513 entries in total would be enough. Nevertheless any
-  /// memory located after the table shall be page aligned (4kb) we will add entries do keep the overall structure
-  /// size fitting exactly into a multiple of a page and to align the following table to a 4kb boundry
-  ttlb_lvl2: [u64; 1024],
-  /*// TLB Level 3 entries will cover a memory range of 4kB each. So to be able to maintain memory attributes on this
-  /// granule level for every memory block we would need 512*512 entries. That's quite a huge amount of memory that is
-  /// most likely wasted, as there will be only a very small amount ob blocks that might require splitting into pages
-  /// from the tlb configuration point of view. So we would start with 3 blocks beeing able to be maintained on this
-  /// granule level which makes 5*512 entries and gives the overall structure a size of a multiple of a page
-  //ttlb_lvl2: [u64; 2560],*/
-} // total size : 6kB
+  /// The granule the translation tables of this region are using
+  granule: Granule,
+  /// The root table the translation table walk of this region starts at
+  root: *mut u64,
+  /// The number of virtual address bits resolved by the translation tables of this region
+  va_bits: usize,
+  /// The access flag handling of the block and page entries of this region
+  access_flag: AccessFlag,
+}
+
+impl MmuConfig {
+  const fn new() -> Self {
+    Self {
+      granule: Granule::_4KB,
+      root: core::ptr::null_mut(),
+      va_bits: config::VA_BITS,
+      access_flag: AccessFlag::Always,
+    }
+  }
+
+  /// The table level the translation table walk of this region starts at
+  const fn start_level(&self) -> usize {
+    self.granule.start_level(self.va_bits)
+  }
+
+  /// The number of entries of the root table of this region
+  const fn root_entries(&self) -> usize {
+    self.granule.root_entries(self.va_bits)
+  }
+
+  /// Whether the virtual address is located within the virtual address range of this region. The lower (TTBR0) region
+  /// covers ``0..1 << va_bits`` and the upper (TTBR1) region ``!0 << va_bits..=!0``, the address selects the region
+  /// the same way ``region_config`` does.
+  const fn contains(&self, va: usize) -> bool {
+    if va >> 63 == 0 {
+      va >> self.va_bits == 0
+    } else {
+      !va >> self.va_bits == 0
+    }
+  }
+
+  /// Apply the access flag handling of this region to the memory attributes of a new block or page entry
+  fn entry_attributes(&self, attributes: u64) -> u64 {
+    let access_flag = TTLB_BLOCKPAGE::AF::SET.raw_value();
+    match self.access_flag {
+      AccessFlag::Always => attributes | access_flag,
+      AccessFlag::Hardware | AccessFlag::Software => attributes & !access_flag,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn page_align_down_keeps_aligned_address() {
+    assert_eq!(page_align_down(0x8_0000), 0x8_0000);
+  }
+
+  #[test]
+  fn page_align_down_rounds_to_page_start() {
+    assert_eq!(page_align_down(0x8_0001), 0x8_0000);
+    assert_eq!(page_align_down(0x8_0FFF), 0x8_0000);
+  }
+
+  #[test]
+  fn page_align_down_keeps_zero() {
+    assert_eq!(page_align_down(0), 0);
+  }
+
+  #[test]
+  fn page_align_down_always_uses_4kb_pages() {
+    // aligned to 4kB but neither to 16kB nor to 64kB pages
+    assert_eq!(page_align_down(0x8_5000), 0x8_5000);
+    assert_eq!(page_align_down(0x8_5FFF), 0x8_5000);
+  }
+
+  #[test]
+  fn page_align_saturates_at_last_page() {
+    assert_eq!(try_page_align(usize::MAX), None);
+    assert_eq!(try_page_align(usize::MAX - 1), None);
+    assert_eq!(page_align(usize::MAX), !config::PAGE_MASK);
+    assert_eq!(page_align(usize::MAX - 1), !config::PAGE_MASK);
+  }
+
+  #[test]
+  fn page_align_rounds_up_to_next_page() {
+    assert_eq!(try_page_align(!config::PAGE_MASK), Some(!config::PAGE_MASK));
+    assert_eq!(page_align(0x8_0001), 0x8_1000);
+    assert_eq!(page_align(0), 0);
+  }
+
+  #[test]
+  fn region_contains_only_its_address_range() {
+    let region = MmuConfig::new();
+    let lower_end = 1 << config::VA_BITS;
+    let upper_start = !0 << config::VA_BITS;
+    assert!(region.contains(0));
+    assert!(region.contains(lower_end - 1));
+    assert!(!region.contains(lower_end));
+    assert!(!region.contains(upper_start - 1));
+    assert!(region.contains(upper_start));
+    assert!(region.contains(!0));
+  }
+}
@@ -48,3 +48,25 @@ macro_rules! define_tlb_entry {
         )*
     };
 }
+
+/// Issue inline assembly for AArch64. Building for any other target, like the host running the unit tests, nothing is
+/// executed: the outputs are set to zero and the inputs are only evaluated. This keeps the translation table code,
+/// which is interleaved with barriers and TLB maintenance, usable on the host.
+#[cfg(target_arch = "aarch64")]
+macro_rules! aarch64_asm {
+  ($($asm:tt)*) => {
+    llvm_asm!($($asm)*)
+  };
+}
+
+/// Issue inline assembly for AArch64 - no-op on any other target
+#[cfg(not(target_arch = "aarch64"))]
+macro_rules! aarch64_asm {
+  ($asm:literal : $($c:literal ($output:expr)),*) => {{ $( $output = Default::default(); )* }};
+  ($asm:literal : $($c:literal ($output:expr)),* : $($rest:tt)*) => {{ $( $output = Default::default(); )* }};
+  ($asm:literal : $($c:literal ($output:expr)),* :: $($rest:tt)*) => {{ $( $output = Default::default(); )* }};
+  ($asm:literal :: $($c:literal ($input:expr)),* :: $($rest:tt)*) => {{ $( let _ = $input; )* }};
+  ($asm:literal :: $($c:literal ($input:expr)),* : $($rest:tt)*) => {{ $( let _ = $input; )* }};
+  ($asm:literal :: $($c:literal ($input:expr)),*) => {{ $( let _ = $input; )* }};
+  ($asm:literal) => {{}};
+}
@@ -0,0 +1,66 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # MMU Statistics
+//!
+//! Counters of the maintenance operations done on the translation tables, the TLB and the caches. They allow to
+//! observe the MMU usage of a long running system, for example to detect virtual address blocks that are mapped but
+//! never unmapped before the virtual address range is exhausted. As atomic operations are not available while the MMU
+//! is disabled, the counters are plain values that are maintained together with the translation tables.
+//!
+
+/// The counters of the MMU maintenance operations
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MmuStats {
+  /// The number of memory regions mapped
+  pub maps: usize,
+  /// The number of memory regions unmapped
+  pub unmaps: usize,
+  /// The number of valid translation table entries replaced following the break-before-make sequence
+  pub remaps: usize,
+  /// The number of TLB invalidations issued
+  pub tlb_flushes: usize,
+  /// The number of cache maintenance operations issued
+  pub cache_maintenance: usize,
+  /// The number of virtual address blocks of the upper (TTBR1) address region currently in use
+  pub va_blocks_used: usize,
+  /// The highest number of virtual address blocks of the upper (TTBR1) address region used at the same time
+  pub va_blocks_peak: usize,
+  /// The number of block entries split into next level tables
+  pub block_splits: usize,
+}
+
+/// The counters of the MMU maintenance operations done so far
+static mut STATS: MmuStats = MmuStats {
+  maps: 0,
+  unmaps: 0,
+  remaps: 0,
+  tlb_flushes: 0,
+  cache_maintenance: 0,
+  va_blocks_used: 0,
+  va_blocks_peak: 0,
+  block_splits: 0,
+};
+
+/// Provide the counters of the MMU maintenance operations done so far
+pub fn stats() -> MmuStats {
+  unsafe { STATS }
+}
+
+/// Update the counters of the MMU maintenance operations with the given function
+pub(crate) fn update(f: impl FnOnce(&mut MmuStats)) {
+  unsafe { f(&mut STATS) };
+}
+
+/// Account the given number of virtual address blocks of the upper address region to be claimed (positive) or
+/// released (negative)
+pub(crate) fn claim_va_blocks(blocks: isize) {
+  update(|stats| {
+    stats.va_blocks_used = (stats.va_blocks_used as isize + blocks).max(0) as usize;
+    stats.va_blocks_peak = stats.va_blocks_peak.max(stats.va_blocks_used);
+  });
+}
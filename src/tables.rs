@@ -0,0 +1,1339 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Translation Table Memory
+//!
+//! The translation tables of both address regions are handed out from a statically reserved memory pool. As a table
+//! always occupies exactly one page of the granule its region is configured with, the pool is maintained in chunks of
+//! the smallest possible table (4kB) and a table of a larger granule occupies the corresponding number of subsequent
+//! chunks.
+//!
+
+use core::mem::{size_of, size_of_val};
+use core::ptr::{read_volatile, write_bytes, write_volatile};
+
+use super::{
+  attributes::MemoryAttributes,
+  cache,
+  config::{Granule, TTLB_BLOCKPAGE, TTLB_TABLE},
+  error::MmuError,
+  hook::{self, MapOp},
+  stats, tlb, ttbr0, ttbr1, MmuConfig,
+};
+
+/// Size of the smallest possible translation table (4kB granule)
+const CHUNK_SIZE: usize = 0x1000;
+/// Number of chunks the table memory pool consists of, giving 512kB of table memory in total
+const CHUNK_COUNT: usize = 128;
+
+/// The pool needs to be aligned to the largest supported granule to allow any table to start at a properly aligned
+/// address
+#[repr(C, align(65536))]
+struct TablePool([[u64; CHUNK_SIZE / 8]; CHUNK_COUNT]);
+
+static mut TABLE_POOL: TablePool = TablePool([[0; CHUNK_SIZE / 8]; CHUNK_COUNT]);
+
+/// Occupation bitmap of the table memory pool, each bit representing one chunk
+static mut POOL_USAGE: [u64; CHUNK_COUNT / 64] = [0; CHUNK_COUNT / 64];
+
+/// The shadow copy of the table memory pool. Each entry written to a table of the pool is written to the same
+/// position of the shadow as well, while the shadow tables are enabled. The shadow is as large as the pool itself, so
+/// it is only reserved with the ``shadow-tables`` feature.
+#[cfg(feature = "shadow-tables")]
+static mut SHADOW_POOL: TablePool = TablePool([[0; CHUNK_SIZE / 8]; CHUNK_COUNT]);
+
+/// Whether the shadow copy of the translation tables is maintained
+#[cfg(feature = "shadow-tables")]
+static mut SHADOW: bool = false;
+
+/// The memory consumed by the translation tables of both address regions
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableUsage {
+  /// The bytes occupied by the translation tables of each level, indexed by the table level
+  pub table_bytes: [usize; 4],
+  /// The number of valid entries of the translation tables of each level, indexed by the table level
+  pub active_entries: [usize; 4],
+  /// The bytes of the table memory pool that are currently occupied by translation tables
+  pub pool_used: usize,
+  /// The bytes statically reserved for the translation tables and their maintenance. All translation tables are
+  /// handed out from this memory, no table is allocated from the heap.
+  pub static_size: usize,
+}
+
+/// Allocate a new and empty translation table for the given granule from the table memory pool. Returns ``None`` if
+/// the pool is exhausted.
+///
+/// # Safety
+/// The pool is not protected against concurrent access. It is up to the caller to ensure only one core maintains
+/// translation tables at a time.
+pub(crate) unsafe fn alloc_table(granule: Granule) -> Option<*mut u64> {
+  let chunks = granule.page_size() / CHUNK_SIZE;
+  // a table is required to be aligned to it's size, so only every n-th chunk is a valid start of a table
+  let first = (0..CHUNK_COUNT)
+    .step_by(chunks)
+    .find(|&first| (first..first + chunks).all(|chunk| !is_chunk_used(chunk)))?;
+
+  for chunk in first..first + chunks {
+    POOL_USAGE[chunk / 64] |= 1 << (chunk % 64);
+  }
+
+  let table = &mut TABLE_POOL.0[first] as *mut _ as *mut u64;
+  write_bytes(table, 0, granule.table_entries());
+  #[cfg(feature = "shadow-tables")]
+  if SHADOW {
+    write_bytes(
+      &mut SHADOW_POOL.0[first] as *mut _ as *mut u64,
+      0,
+      granule.table_entries(),
+    );
+  }
+  Some(table)
+}
+
+/// Return a translation table handed out by ``alloc_table`` to the table memory pool. Each table is referred to by
+/// exactly one table entry, so the table is free as soon as this entry is gone. The only table shared by several
+/// entries is the sentinel table of the upper address region, which is never released on its own but only together
+/// with the whole pool.
+///
+/// # Safety
+/// The table need to be allocated with the same granule and the table entry referring to it shall no longer be valid.
+pub(crate) unsafe fn release_table(table: *mut u64, granule: Granule) {
+  let first = (table as usize - &TABLE_POOL as *const _ as usize) / CHUNK_SIZE;
+  for chunk in first..first + granule.page_size() / CHUNK_SIZE {
+    POOL_USAGE[chunk / 64] &= !(1 << (chunk % 64));
+  }
+}
+
+/// Return all translation tables to the table memory pool and clear the whole pool memory. After a soft reset that
+/// did not clear the ``.bss`` section the pool still contains the tables and the bookkeeping of the previous run, so
+/// the initial setup starts from this clean state instead of inheriting stale entries.
+///
+/// # Safety
+/// No translation table of the pool shall be in use by the MMU of any core.
+pub(crate) unsafe fn reset_pool() {
+  write_bytes(&mut TABLE_POOL as *mut TablePool, 0, 1);
+  #[cfg(feature = "shadow-tables")]
+  write_bytes(&mut SHADOW_POOL as *mut TablePool, 0, 1);
+  POOL_USAGE = [0; CHUNK_COUNT / 64];
+}
+
+/// Choose whether the shadow copy of the translation tables is maintained. Enabling it takes a copy of the current
+/// table memory pool, so the shadow starts in sync with the live tables.
+///
+/// # Safety
+/// No other core shall maintain the translation tables at the same time.
+#[cfg(feature = "shadow-tables")]
+pub(crate) unsafe fn set_shadow(enabled: bool) {
+  if enabled && !SHADOW {
+    core::ptr::copy_nonoverlapping(
+      &TABLE_POOL as *const TablePool,
+      &mut SHADOW_POOL as *mut TablePool,
+      1,
+    );
+  }
+  SHADOW = enabled;
+}
+
+/// Choose whether the shadow copy of the translation tables is maintained - no-op without the ``shadow-tables``
+/// feature
+#[cfg(not(feature = "shadow-tables"))]
+#[inline(always)]
+pub(crate) unsafe fn set_shadow(_enabled: bool) {}
+
+/// Compare the entries of all translation tables handed out from the table memory pool with their shadow copy.
+/// Returns the index of the first entry within the pool that differs. The access flag is not compared as it may be
+/// set by the hardware.
+///
+/// # Safety
+/// No other core shall maintain the translation tables at the same time.
+#[cfg(feature = "shadow-tables")]
+pub(crate) unsafe fn verify_shadow() -> Result<(), usize> {
+  if !SHADOW {
+    return Ok(());
+  }
+
+  let access_flag = TTLB_BLOCKPAGE::AF::SET.raw_value();
+  let entries = CHUNK_SIZE / 8;
+  for chunk in (0..CHUNK_COUNT).filter(|&chunk| is_chunk_used(chunk)) {
+    let live = &TABLE_POOL.0[chunk] as *const _ as *const u64;
+    let shadow = &SHADOW_POOL.0[chunk] as *const _ as *const u64;
+    if let Some(idx) = (0..entries)
+      .find(|&idx| (read_entry(live, idx) ^ read_entry(shadow, idx)) & !access_flag != 0)
+    {
+      return Err(chunk * entries + idx);
+    }
+  }
+  Ok(())
+}
+
+/// Compare the translation tables with their shadow copy - always succeeds without the ``shadow-tables`` feature
+#[cfg(not(feature = "shadow-tables"))]
+#[inline(always)]
+pub(crate) unsafe fn verify_shadow() -> Result<(), usize> {
+  Ok(())
+}
+
+/// Maintains additional translation table entries while the MMU is initialized. The builder is handed to the
+/// ``post_setup`` callback of ``initialize_with`` after the default 1:1 mapping has been set up and before the MMU is
+/// activated.
+pub struct TableBuilder {
+  _private: (),
+}
+
+impl TableBuilder {
+  pub(crate) fn new() -> Self {
+    Self { _private: () }
+  }
+
+  /// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` with the given memory attributes.
+  /// The virtual address selects the lower (TTBR0) or the upper (TTBR1) address region. The range is mapped with
+  /// the largest blocks possible, existing mappings of the range are replaced.
+  ///
+  /// # Safety
+  /// The new mapping shall not change the memory the initialization code is running from.
+  /// # Errors
+  /// ``MmuError::Unaligned`` if the addresses are not aligned to the page size of the address region,
+  /// ``MmuError::SizeOverflow`` if the virtual or the physical range wraps around the end of the address space,
+  /// ``MmuError::NoVirtualMapping`` if there are no translation tables for the address region,
+  /// ``MmuError::WouldCorruptTables`` if the range covers the memory of the translation tables and the attributes are
+  /// not the ones the translation table walk is configured with and ``MmuError::TableMemoryExhausted`` if there is no
+  /// memory left for the required translation tables.
+  pub unsafe fn map(
+    &mut self,
+    va: usize,
+    pa: usize,
+    size: usize,
+    attributes: MemoryAttributes,
+  ) -> Result<(), MmuError> {
+    map_region(va, pa, size, attributes)
+  }
+
+  /// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` with level 2 block entries only,
+  /// like ``map`` does for a range that can be represented with blocks without any page (2MB blocks with the default
+  /// granule). Parts of the range that are already split into pages keep their page entries.
+  ///
+  /// # Safety
+  /// Same as for ``map``.
+  /// # Errors
+  /// ``MmuError::Unaligned`` if the addresses are not aligned to the block size of the address region,
+  /// ``MmuError::SizeNotBlockAligned`` if the size is not a multiple of the block size, so the range need to be mapped
+  /// with ``map`` using blocks and pages, ``MmuError::SizeOverflow`` under the same conditions as for ``map``,
+  /// ``MmuError::NoVirtualMapping`` if there are no translation tables for the
+  /// address region, ``MmuError::WouldCorruptTables`` under the same conditions as for ``map`` and
+  /// ``MmuError::TableMemoryExhausted`` if there is no memory left for the required translation tables.
+  pub unsafe fn map_blocks(
+    &mut self,
+    va: usize,
+    pa: usize,
+    size: usize,
+    attributes: MemoryAttributes,
+  ) -> Result<(), MmuError> {
+    map_block_region(va, pa, size, attributes)
+  }
+}
+
+/// Maintains translation table entries while the MMU is active. The builder is handed to the closure of
+/// ``reconfigure``. All changes are done following the break-before-make sequence with the required TLB maintenance,
+/// so the MMU stays enabled and atomic operations keep working while the mappings are changed.
+pub struct ReconfigBuilder {
+  _private: (),
+}
+
+impl ReconfigBuilder {
+  pub(crate) fn new() -> Self {
+    Self { _private: () }
+  }
+
+  /// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` with the given memory attributes.
+  /// The virtual address selects the lower (TTBR0) or the upper (TTBR1) address region. The range is mapped with
+  /// the largest blocks possible, existing mappings of the range are replaced.
+  ///
+  /// # Safety
+  /// The memory of the range shall not be accessed while it is re-maintained and the new mapping shall not change the
+  /// memory the calling code is running from.
+  /// # Errors
+  /// ``MmuError::Unaligned`` if the addresses are not aligned to the page size of the address region,
+  /// ``MmuError::SizeOverflow`` if the virtual or the physical range wraps around the end of the address space,
+  /// ``MmuError::NoVirtualMapping`` if there are no translation tables for the address region,
+  /// ``MmuError::WouldCorruptTables`` if the range covers the memory of the translation tables and the attributes are
+  /// not the ones the translation table walk is configured with and ``MmuError::TableMemoryExhausted`` if there is no
+  /// memory left for the required translation tables.
+  pub unsafe fn map(
+    &mut self,
+    va: usize,
+    pa: usize,
+    size: usize,
+    attributes: MemoryAttributes,
+  ) -> Result<(), MmuError> {
+    map_region(va, pa, size, attributes)
+  }
+
+  /// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` with level 2 block entries only,
+  /// like ``map`` does for a range that can be represented with blocks without any page (2MB blocks with the default
+  /// granule). Parts of the range that are already split into pages keep their page entries.
+  ///
+  /// # Safety
+  /// Same as for ``map``.
+  /// # Errors
+  /// ``MmuError::Unaligned`` if the addresses are not aligned to the block size of the address region,
+  /// ``MmuError::SizeNotBlockAligned`` if the size is not a multiple of the block size, so the range need to be mapped
+  /// with ``map`` using blocks and pages, ``MmuError::SizeOverflow`` under the same conditions as for ``map``,
+  /// ``MmuError::NoVirtualMapping`` if there are no translation tables for the
+  /// address region, ``MmuError::WouldCorruptTables`` under the same conditions as for ``map`` and
+  /// ``MmuError::TableMemoryExhausted`` if there is no memory left for the required translation tables.
+  pub unsafe fn map_blocks(
+    &mut self,
+    va: usize,
+    pa: usize,
+    size: usize,
+    attributes: MemoryAttributes,
+  ) -> Result<(), MmuError> {
+    map_block_region(va, pa, size, attributes)
+  }
+
+  /// Change the memory attributes of the already mapped virtual memory range ``va..va + size``. The output addresses
+  /// are kept, blocks only partially covered by the range are split into pages. If the range becomes executable the
+  /// instruction cache is synchronized with it (see ``sync_instruction_cache``).
+  ///
+  /// # Safety
+  /// The memory of the range shall not be accessed while it's attributes are changed.
+  /// # Errors
+  /// ``MmuError::WouldCorruptTables`` if the range is mapped to the memory of the translation tables and the
+  /// attributes are not the ones the translation table walk is configured with, ``MmuError::SizeOverflow`` if the range
+  /// wraps around the end of the address space and ``MmuError::NotMapped`` if any part of the range is not mapped or a
+  /// block could not be split.
+  pub unsafe fn set_attributes(
+    &mut self,
+    va: usize,
+    size: usize,
+    attributes: MemoryAttributes,
+  ) -> Result<(), MmuError> {
+    if wraps_around(va, size) {
+      return Err(MmuError::SizeOverflow);
+    }
+    let config = region_config(va);
+    if !attributes.is_table_walk_compatible() && maps_table_memory(config, va, size) {
+      return Err(MmuError::WouldCorruptTables);
+    }
+    // all bits except the entry type and output address are replaced
+    let mask = MemoryAttributes::from_raw(!0).raw();
+    protect_range(config, va, size, attributes.raw(), mask).ok_or(MmuError::NotMapped)?;
+    let pa = translate(config, va).map_or(0, |(pa, _)| pa);
+    hook::notify(MapOp::Remap, pa, va, size, attributes.raw());
+    // code written to the range before it became executable shall not be shadowed by stale instructions
+    if attributes.is_executable() {
+      cache::sync_instruction_cache(va as *const u8, size);
+    }
+    Ok(())
+  }
+}
+
+/// The translation table configuration of the address region the virtual address is located in
+///
+/// # Safety
+/// The configuration is only valid once the translation tables are set up
+pub(crate) unsafe fn region_config(va: usize) -> &'static MmuConfig {
+  if va >> 63 == 0 {
+    ttbr0::mmu_config()
+  } else {
+    ttbr1::mmu_config()
+  }
+}
+
+/// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` within the address region the
+/// virtual address is located in
+unsafe fn map_region(
+  va: usize,
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<(), MmuError> {
+  let config = region_config(va);
+  if config.root.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  if (va | pa) & (config.granule.page_size() - 1) != 0 {
+    return Err(MmuError::unaligned(va | pa, config.granule.page_size()));
+  }
+  if wraps_around(va, size) || wraps_around(pa, size) {
+    return Err(MmuError::SizeOverflow);
+  }
+  // accessing the translation tables with a different memory type would break the translation table walk
+  if overlaps_table_memory(pa, size) && !attributes.is_table_walk_compatible() {
+    return Err(MmuError::WouldCorruptTables);
+  }
+  map_range(config, va, pa, size, attributes.raw()).ok_or(MmuError::TableMemoryExhausted)?;
+  stats::update(|stats| stats.maps += 1);
+  hook::notify(MapOp::Map, pa, va, size, attributes.raw());
+  Ok(())
+}
+
+/// Map the physical memory range ``pa..pa + size`` to the virtual address ``va`` within the address region the
+/// virtual address is located in using block entries only
+unsafe fn map_block_region(
+  va: usize,
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<(), MmuError> {
+  let block_mask = (1 << region_config(va).granule.block_shift()) - 1;
+  if (va | pa) & block_mask != 0 {
+    return Err(MmuError::unaligned(va | pa, block_mask + 1));
+  }
+  // rounding the size up would silently map more memory than requested
+  if size & block_mask != 0 {
+    return Err(MmuError::SizeNotBlockAligned);
+  }
+  map_region(va, pa, size, attributes)
+}
+
+/// Check whether the physical memory range ``pa..pa + size`` overlaps the memory the translation tables are handed out
+/// from
+pub(crate) fn overlaps_table_memory(pa: usize, size: usize) -> bool {
+  // the table memory is covered by the 1:1 mapping, so it's address is the physical one
+  let pool_start = unsafe { &TABLE_POOL as *const _ as usize };
+  let pool_end = pool_start + size_of::<TablePool>();
+  pa < pool_end && pool_start < pa.saturating_add(size)
+}
+
+/// Check whether the address range ``addr..addr + size`` wraps around the end of the address space. A range may end
+/// right at the end of the address space, like the last page of the upper address region.
+pub(crate) fn wraps_around(addr: usize, size: usize) -> bool {
+  size != 0 && addr.checked_add(size - 1).is_none()
+}
+
+/// Check whether any page of the virtual memory range ``va..va + size`` is mapped to the memory the translation tables
+/// are handed out from
+///
+/// # Safety
+/// The translation tables of the address region shall not be changed concurrently
+unsafe fn maps_table_memory(config: &MmuConfig, va: usize, size: usize) -> bool {
+  let end = va.saturating_add(size);
+  let mut current = va & !(config.granule.page_size() - 1);
+  while current < end {
+    let step = match translate(config, current) {
+      Some((pa, entry_size)) => {
+        // the remainder of the block or page this address is mapped with
+        let remaining = entry_size - (current & (entry_size - 1));
+        if overlaps_table_memory(pa, remaining) {
+          return true;
+        }
+        remaining
+      }
+      None => config.granule.page_size(),
+    };
+    current = match current.checked_add(step) {
+      Some(next) => next,
+      None => break,
+    };
+  }
+  false
+}
+
+/// The location of a translation table entry
+#[derive(Copy, Clone)]
+pub(crate) struct TableEntry {
+  /// the translation table containing the entry
+  pub table: *mut u64,
+  /// the level of the translation table
+  pub level: usize,
+  /// the index of the entry within the translation table
+  pub index: usize,
+}
+
+/// Walk the translation tables of an address region down to the block, page or invalid entry covering the given
+/// virtual address - the same way the hardware table walk does. Returns ``None`` if the address is located outside of
+/// the virtual address range of the region, where the hardware reports a translation fault without any table walk.
+///
+/// # Safety
+/// The translation tables of the address region need to be set up already
+pub(crate) unsafe fn lookup(config: &MmuConfig, va: usize) -> Option<TableEntry> {
+  if !config.contains(va) {
+    return None;
+  }
+  let granule = config.granule;
+  let mut table = config.root;
+  let mut level = config.start_level();
+  let mut entries = config.root_entries();
+  loop {
+    let index = (va >> granule.level_shift(level)) & (entries - 1);
+    let entry = read_entry(table, index);
+    if !is_table(level, entry) {
+      return Some(TableEntry {
+        table,
+        level,
+        index,
+      });
+    }
+    table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+    level += 1;
+    entries = granule.table_entries();
+  }
+}
+
+/// Translate the virtual address ``va`` into the output address the translation tables of the address region map it
+/// to. Returns the output address together with the size of the block or page the address is located in or ``None``
+/// if the address is not mapped.
+///
+/// # Safety
+/// The result is only meaningful as long as the translation tables are not changed concurrently
+pub(crate) unsafe fn translate(config: &MmuConfig, va: usize) -> Option<(usize, usize)> {
+  if config.root.is_null() {
+    return None;
+  }
+
+  let entry = lookup(config, va)?;
+  let value = read_entry(entry.table, entry.index);
+  if !is_valid(value) {
+    return None;
+  }
+  let entry_size = 1 << config.granule.level_shift(entry.level);
+  let output = TTLB_BLOCKPAGE::ADDR::from_raw(value).raw_value() as usize;
+  Some((
+    (output & !(entry_size - 1)) | (va & (entry_size - 1)),
+    entry_size,
+  ))
+}
+
+/// Replace a translation table entry that may be cached in the TLB following the break-before-make sequence. The
+/// entry is invalidated and all cached translations of the virtual address ``va`` it covers are flushed before the
+/// new entry is written.
+///
+/// # Safety
+/// While the entry is invalid any access to the memory it covers will fault.
+pub(crate) unsafe fn replace_entry(entry: &TableEntry, va: usize, value: u64) {
+  stats::update(|stats| stats.remaps += 1);
+  write_entry(entry.table, entry.index, 0);
+  aarch64_asm!("dsb   ishst");
+  tlb::invalidate_va(va);
+  write_entry(entry.table, entry.index, value);
+  aarch64_asm!(
+    "dsb   ishst
+     isb"
+  );
+}
+
+/// Split a block entry into a next level table of blocks or pages with the same memory attributes covering the same
+/// output addresses. The block entry is replaced with the table entry following the break-before-make sequence.
+/// Returns ``None`` if there is no memory left for the new table.
+///
+/// # Safety
+/// The entry need to be a valid block entry covering the virtual address ``va``
+pub(crate) unsafe fn split_block(config: &MmuConfig, entry: &TableEntry, va: usize) -> Option<()> {
+  let granule = config.granule;
+  let block = read_entry(entry.table, entry.index);
+  let next_table = alloc_table(granule)?;
+  stats::update(|stats| stats.block_splits += 1);
+  let next_level = entry.level + 1;
+  let next_shift = granule.level_shift(next_level);
+  let next_type = if next_level == 3 {
+    TTLB_BLOCKPAGE::TYPE::PAGE
+  } else {
+    TTLB_BLOCKPAGE::TYPE::BLOCK
+  };
+  // the new entries share the memory attributes of the block and cover the same output address range
+  let attributes = MemoryAttributes::from_raw(block).raw();
+  let block_addr = TTLB_BLOCKPAGE::ADDR::from_raw(block).raw_value();
+  for idx in 0..granule.table_entries() {
+    write_entry(
+      next_table,
+      idx,
+      attributes
+        | (next_type | TTLB_BLOCKPAGE::ADDR::from_raw(block_addr + ((idx as u64) << next_shift)))
+          .raw_value(),
+    );
+  }
+  if next_level == 3 {
+    mark_contiguous(granule, next_table);
+  }
+
+  replace_entry(
+    entry,
+    va,
+    (TTLB_TABLE::NS::SET | TTLB_TABLE::TYPE::VALID | TTLB_TABLE::ADDR::from_raw(next_table as u64))
+      .raw_value(),
+  );
+  Some(())
+}
+
+/// Collapse the next level table a table entry refers to back into a single block entry. This is only possible if the
+/// entries of the next level table are all valid, share the same memory attributes and cover one contiguous output
+/// address range that is aligned to the size of the block. The table entry is replaced with the block entry following
+/// the break-before-make sequence and the next level table is released. Returns ``false`` if the table could not be
+/// collapsed.
+///
+/// # Safety
+/// The entry need to be a table entry covering the virtual address ``va``
+pub(crate) unsafe fn collapse_pages_to_block(
+  config: &MmuConfig,
+  entry: &TableEntry,
+  va: usize,
+) -> bool {
+  let granule = config.granule;
+  let value = read_entry(entry.table, entry.index);
+  if !granule.supports_block(entry.level) || !is_table(entry.level, value) {
+    return false;
+  }
+
+  let next_table = TTLB_TABLE::ADDR::from_raw(value).raw_value() as *mut u64;
+  let next_level = entry.level + 1;
+  let next_shift = granule.level_shift(next_level);
+  let next_type = if next_level == 3 {
+    TTLB_BLOCKPAGE::TYPE::PAGE
+  } else {
+    TTLB_BLOCKPAGE::TYPE::BLOCK
+  };
+  // the contiguous hint of the pages does not apply to the block
+  let contiguous = TTLB_BLOCKPAGE::C::with_value(1).raw_value();
+  let first = read_entry(next_table, 0);
+  let attributes = MemoryAttributes::from_raw(first).raw();
+  let block_addr = TTLB_BLOCKPAGE::ADDR::from_raw(first).raw_value();
+  if block_addr & ((1 << granule.level_shift(entry.level)) - 1) != 0 {
+    return false;
+  }
+
+  let collapsible = (0..granule.table_entries()).all(|idx| {
+    read_entry(next_table, idx) & !contiguous
+      == attributes
+        | (next_type | TTLB_BLOCKPAGE::ADDR::from_raw(block_addr + ((idx as u64) << next_shift)))
+          .raw_value()
+  });
+  if !collapsible {
+    return false;
+  }
+
+  replace_entry(
+    entry,
+    va,
+    attributes
+      | (TTLB_BLOCKPAGE::TYPE::BLOCK | TTLB_BLOCKPAGE::ADDR::from_raw(block_addr)).raw_value(),
+  );
+  // the block entry no longer refers to the next level table
+  release_table(next_table, granule);
+  true
+}
+
+/// Collapse the level 3 table backing the block that covers the virtual address ``va`` back into a block entry. Returns
+/// ``false`` if there is no such level 3 table or it could not be collapsed.
+///
+/// # Safety
+/// The memory covered by the block shall not be accessed while it is re-maintained.
+pub(crate) unsafe fn promote_block(config: &MmuConfig, va: usize) -> bool {
+  if config.root.is_null() {
+    return false;
+  }
+
+  let granule = config.granule;
+  let block_level = 2;
+  let mut table = config.root;
+  let mut level = config.start_level();
+  let mut entries = config.root_entries();
+  // walk down to the table containing the block level entry
+  while level < block_level {
+    let entry = read_entry(table, (va >> granule.level_shift(level)) & (entries - 1));
+    if !is_table(level, entry) {
+      return false;
+    }
+    table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+    level += 1;
+    entries = granule.table_entries();
+  }
+
+  let entry = TableEntry {
+    table,
+    level,
+    index: (va >> granule.level_shift(level)) & (entries - 1),
+  };
+  collapse_pages_to_block(config, &entry, va)
+}
+
+/// Map the output address range ``pa..pa + size`` to the virtual address ``va`` with the given memory attributes using
+/// the largest blocks the alignment of the addresses allows. Missing next level tables are allocated and blocks only
+/// partially covered by the range are split. Returns ``None`` if there is no memory left for a translation table or
+/// either address range wraps around the end of the address space.
+///
+/// # Safety
+/// The addresses need to be aligned to the page size of the address region. Valid entries are replaced following the
+/// break-before-make sequence, so the range shall not be accessed while it is re-maintained.
+pub(crate) unsafe fn map_range(
+  config: &MmuConfig,
+  va: usize,
+  pa: usize,
+  size: usize,
+  attributes: u64,
+) -> Option<()> {
+  // a removed mapping of the range might still be cached if it's invalidation has been deferred
+  tlb::flush_pending();
+  let attributes = config.entry_attributes(attributes);
+  let granule = config.granule;
+  let page_mask = granule.page_size() - 1;
+  let size = size.checked_add(page_mask)? & !page_mask;
+  if wraps_around(va, size) || wraps_around(pa, size) {
+    return None;
+  }
+
+  let mut offset = 0;
+  while offset < size {
+    let addr = va + offset;
+    let out = pa + offset;
+    let mut table = config.root;
+    let mut level = config.start_level();
+    let mut entries = config.root_entries();
+    loop {
+      let shift = granule.level_shift(level);
+      let entry_size = 1 << shift;
+      let index = (addr >> shift) & (entries - 1);
+      let value = read_entry(table, index);
+      let is_table = is_table(level, value);
+      let fits = (level == 3 || granule.supports_block(level))
+        && (addr | out) & (entry_size - 1) == 0
+        && size - offset >= entry_size;
+
+      if fits && !is_table {
+        let entry_type = if level == 3 {
+          TTLB_BLOCKPAGE::TYPE::PAGE
+        } else {
+          TTLB_BLOCKPAGE::TYPE::BLOCK
+        };
+        let new_value =
+          attributes | (entry_type | TTLB_BLOCKPAGE::ADDR::from_raw(out as u64)).raw_value();
+        if level == 3 {
+          break_contiguous(config, table, index, addr);
+        }
+        if is_cached(value) {
+          // the entry might be cached already
+          replace_entry(
+            &TableEntry {
+              table,
+              level,
+              index,
+            },
+            addr,
+            new_value,
+          );
+        } else {
+          write_entry(table, index, new_value);
+        }
+        offset += entry_size;
+        break;
+      }
+
+      if !is_table {
+        // a more granular mapping is required, so provide the next level table and re-visit the entry
+        let entry = TableEntry {
+          table,
+          level,
+          index,
+        };
+        if is_valid(value) {
+          split_block(config, &entry, addr)?;
+        } else {
+          let next_table = alloc_table(granule)?;
+          let table_entry = (TTLB_TABLE::NS::SET
+            | TTLB_TABLE::TYPE::VALID
+            | TTLB_TABLE::ADDR::from_raw(next_table as u64))
+          .raw_value();
+          if is_cached(value) {
+            replace_entry(&entry, addr, table_entry);
+          } else {
+            write_entry(table, index, table_entry);
+          }
+        }
+        continue;
+      }
+
+      table = TTLB_TABLE::ADDR::from_raw(value).raw_value() as *mut u64;
+      level += 1;
+      entries = granule.table_entries();
+    }
+  }
+
+  aarch64_asm!(
+    "dsb   ishst
+     isb"
+  );
+  Some(())
+}
+
+/// Change the memory attributes of all pages covering the virtual address range ``va..va + size``. The bits given by
+/// ``mask`` are replaced with the ``attributes`` in each entry. Block entries only partially covered by the range are
+/// split into pages first. Returns ``None`` if any part of the range is not mapped or a block could not be split.
+/// This includes address regions without translation tables and ranges wrapping around the end of the address space.
+///
+/// # Safety
+/// The translation tables of the address region need to be set up already and the memory of the range shall not be
+/// accessed while it's attributes are changed.
+pub(crate) unsafe fn protect_range(
+  config: &MmuConfig,
+  va: usize,
+  size: usize,
+  attributes: u64,
+  mask: u64,
+) -> Option<()> {
+  if config.root.is_null() || wraps_around(va, size) {
+    return None;
+  }
+  if size == 0 {
+    return Some(());
+  }
+
+  let page_mask = config.granule.page_size() - 1;
+  let start = va & !page_mask;
+  // the last address of the range, which may be the last address of the address space
+  let last = (va + (size - 1)) | page_mask;
+
+  let mut addr = start;
+  while addr <= last {
+    let entry = lookup(config, addr)?;
+    if entry.level == 3 {
+      break_contiguous(config, entry.table, entry.index, addr);
+    }
+    let value = read_entry(entry.table, entry.index);
+    if !is_valid(value) {
+      return None;
+    }
+
+    let entry_size = 1 << config.granule.level_shift(entry.level);
+    let entry_start = addr & !(entry_size - 1);
+    let entry_last = entry_start + (entry_size - 1);
+    if entry.level < 3 && (entry_start < start || entry_last > last) {
+      // the block is only partially covered, so split it and re-visit the same address
+      split_block(config, &entry, addr)?;
+    } else {
+      replace_entry(&entry, addr, (value & !mask) | attributes);
+      addr = match entry_last.checked_add(1) {
+        Some(next) => next,
+        None => break,
+      };
+    }
+  }
+
+  Some(())
+}
+
+/// Provide the memory consumed by the translation tables of the given address regions
+///
+/// # Safety
+/// The result is only meaningful as long as the translation tables are not changed concurrently
+pub(crate) unsafe fn usage(configs: &[&MmuConfig]) -> TableUsage {
+  let used_chunks: u32 = POOL_USAGE.iter().map(|usage| usage.count_ones()).sum();
+  let mut usage = TableUsage {
+    pool_used: used_chunks as usize * CHUNK_SIZE,
+    static_size: size_of::<TablePool>()
+      + size_of_val(&POOL_USAGE)
+      + if cfg!(feature = "shadow-tables") {
+        size_of::<TablePool>()
+      } else {
+        0
+      }
+      + configs.len() * size_of::<MmuConfig>(),
+    ..TableUsage::default()
+  };
+
+  for config in configs.iter().filter(|config| !config.root.is_null()) {
+    let granule = config.granule;
+    count_table(
+      granule,
+      config.root,
+      config.start_level(),
+      config.root_entries(),
+      &mut usage,
+    );
+  }
+
+  usage
+}
+
+/// Account the given translation table and all next level tables it refers to
+unsafe fn count_table(
+  granule: Granule,
+  table: *const u64,
+  level: usize,
+  entries: usize,
+  usage: &mut TableUsage,
+) {
+  // a table always occupies a whole page of it's granule, even the root table with less entries
+  usage.table_bytes[level] += granule.page_size();
+  for idx in 0..entries {
+    let entry = read_entry(table, idx);
+    if !is_valid(entry) {
+      continue;
+    }
+    usage.active_entries[level] += 1;
+    if is_table(level, entry) {
+      let next_table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *const u64;
+      count_table(
+        granule,
+        next_table,
+        level + 1,
+        granule.table_entries(),
+        usage,
+      );
+    }
+  }
+}
+
+/// The magic value the serialized translation tables start with
+const EXPORT_MAGIC: u64 = u64::from_le_bytes(*b"MMUTABLE");
+/// The version of the layout of the serialized translation tables
+const EXPORT_VERSION: u64 = 1;
+
+/// Writes little endian 64 bit values one after another into a byte buffer
+struct ExportWriter<'a> {
+  buf: &'a mut [u8],
+  offset: usize,
+}
+
+impl ExportWriter<'_> {
+  fn put(&mut self, value: u64) -> Result<(), MmuError> {
+    let end = self.offset + size_of::<u64>();
+    self
+      .buf
+      .get_mut(self.offset..end)
+      .ok_or(MmuError::BufferTooSmall)?
+      .copy_from_slice(&value.to_le_bytes());
+    self.offset = end;
+    Ok(())
+  }
+}
+
+/// Serialize the translation tables of the given address regions, each given with the virtual address it starts at,
+/// into the buffer and return the number of bytes written. The layout is described at ``export_tables``.
+///
+/// # Safety
+/// The translation tables shall not be changed while they are serialized
+pub(crate) unsafe fn export(
+  regions: &[(&MmuConfig, usize)],
+  buf: &mut [u8],
+) -> Result<usize, MmuError> {
+  let mut writer = ExportWriter { buf, offset: 0 };
+  writer.put(EXPORT_MAGIC)?;
+  writer.put(EXPORT_VERSION)?;
+  // the number of tables is only known once all of them are written
+  let count_offset = writer.offset;
+  writer.put(0)?;
+  for (config, _) in regions {
+    if config.root.is_null() {
+      writer.put(0)?;
+      writer.put(0)?;
+      writer.put(0)?;
+    } else {
+      writer.put(config.granule.page_shift() as u64)?;
+      writer.put(config.va_bits as u64)?;
+      writer.put(config.start_level() as u64)?;
+    }
+  }
+
+  let mut count = 0;
+  for (region, (config, base)) in regions.iter().enumerate() {
+    if !config.root.is_null() {
+      count += export_table(
+        &mut writer,
+        region as u64,
+        config.granule,
+        config.root,
+        config.start_level(),
+        config.root_entries(),
+        *base,
+      )?;
+    }
+  }
+
+  writer.buf[count_offset..count_offset + size_of::<u64>()].copy_from_slice(&count.to_le_bytes());
+  Ok(writer.offset)
+}
+
+/// Serialize the given table followed by all next level tables it refers to and return the number of tables written
+unsafe fn export_table(
+  writer: &mut ExportWriter,
+  region: u64,
+  granule: Granule,
+  table: *const u64,
+  level: usize,
+  entries: usize,
+  base: usize,
+) -> Result<u64, MmuError> {
+  writer.put(region)?;
+  writer.put(level as u64)?;
+  writer.put(table as u64)?;
+  writer.put(base as u64)?;
+  writer.put(entries as u64)?;
+  for idx in 0..entries {
+    writer.put(read_entry(table, idx))?;
+  }
+
+  let mut count = 1;
+  if level < 3 {
+    let shift = granule.level_shift(level);
+    for idx in 0..entries {
+      let entry = read_entry(table, idx);
+      if is_table(level, entry) {
+        let next_table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *const u64;
+        count += export_table(
+          writer,
+          region,
+          granule,
+          next_table,
+          level + 1,
+          granule.table_entries(),
+          base.wrapping_add(idx << shift),
+        )?;
+      }
+    }
+  }
+
+  Ok(count)
+}
+
+/// Set the contiguous hint in each group of pages of a level 3 table that are valid, share the same memory attributes
+/// and cover one contiguous output address range aligned to the size of the group. The TLB may then cache one
+/// translation for the whole group. Groups not fulfilling this are left unchanged.
+///
+/// # Safety
+/// The table need to be a level 3 table that is not yet in use, as the entries are updated without break-before-make.
+pub(crate) unsafe fn mark_contiguous(granule: Granule, table: *mut u64) {
+  let group = granule.contiguous_pages();
+  let contiguous = TTLB_BLOCKPAGE::C::with_value(1).raw_value();
+  for first in (0..granule.table_entries()).step_by(group) {
+    let value = read_entry(table, first);
+    let attributes = MemoryAttributes::from_raw(value).raw();
+    let group_addr = TTLB_BLOCKPAGE::ADDR::from_raw(value).raw_value();
+    if !is_valid(value) || group_addr & (((group as u64) << granule.page_shift()) - 1) != 0 {
+      continue;
+    }
+
+    let uniform = (0..group).all(|idx| {
+      read_entry(table, first + idx) & !contiguous
+        == attributes
+          | (TTLB_BLOCKPAGE::TYPE::PAGE
+            | TTLB_BLOCKPAGE::ADDR::from_raw(group_addr + ((idx as u64) << granule.page_shift())))
+          .raw_value()
+    });
+    if uniform {
+      for idx in first..first + group {
+        write_entry(table, idx, read_entry(table, idx) | contiguous);
+      }
+    }
+  }
+}
+
+/// Clear the contiguous hint of the group of pages the page entry at ``index`` of the level 3 ``table`` belongs to,
+/// before a single page of the group is changed. As the group might be cached as a single translation, all pages of
+/// the group are re-maintained following the break-before-make sequence at once. ``va`` is the virtual address the
+/// page entry covers. Pages without the contiguous hint are not touched.
+///
+/// # Safety
+/// While the group is re-maintained any access to the memory it covers faults.
+unsafe fn break_contiguous(config: &MmuConfig, table: *mut u64, index: usize, va: usize) {
+  let contiguous = TTLB_BLOCKPAGE::C::with_value(1).raw_value();
+  if read_entry(table, index) & contiguous == 0 {
+    return;
+  }
+
+  let granule = config.granule;
+  let group = granule.contiguous_pages();
+  let first = index & !(group - 1);
+  let group_va = va & !((group << granule.page_shift()) - 1);
+  for idx in first..first + group {
+    let value = read_entry(table, idx);
+    if is_cached(value) && value & contiguous != 0 {
+      write_entry(table, idx, value & !0b1);
+    }
+  }
+  aarch64_asm!("dsb   ishst");
+  for page in 0..group {
+    tlb::invalidate_va(group_va + (page << granule.page_shift()));
+  }
+  for idx in first..first + group {
+    let value = read_entry(table, idx);
+    if value & contiguous != 0 {
+      write_entry(table, idx, (value & !contiguous) | 0b1);
+    }
+  }
+  aarch64_asm!(
+    "dsb   ishst
+     isb"
+  );
+}
+
+/// Remove the mapping of all blocks and pages covering the virtual address range ``va..va + size``. Block entries
+/// only partially covered by the range are split into pages first. Returns ``None`` if a block could not be split or
+/// the range wraps around the end of the address space.
+///
+/// # Safety
+/// The memory of the range shall not be accessed any longer.
+pub(crate) unsafe fn clear_range(config: &MmuConfig, va: usize, size: usize) -> Option<()> {
+  if wraps_around(va, size) {
+    return None;
+  }
+  if config.root.is_null() || size == 0 {
+    return Some(());
+  }
+
+  let page_mask = config.granule.page_size() - 1;
+  let start = va & !page_mask;
+  // the last address of the range, which may be the last address of the address space
+  let last = (va + (size - 1)) | page_mask;
+
+  let mut addr = start;
+  while addr <= last {
+    // nothing is mapped beyond the virtual address range of the region
+    let entry = match lookup(config, addr) {
+      Some(entry) => entry,
+      None => break,
+    };
+    let value = read_entry(entry.table, entry.index);
+    let entry_size = 1 << config.granule.level_shift(entry.level);
+    let entry_start = addr & !(entry_size - 1);
+    let entry_last = entry_start + (entry_size - 1);
+    if is_valid(value) && entry.level < 3 && (entry_start < start || entry_last > last) {
+      // the block is only partially covered, so split it and re-visit the same address
+      split_block(config, &entry, addr)?;
+    } else {
+      if is_valid(value) {
+        if entry.level == 3 {
+          break_contiguous(config, entry.table, entry.index, addr);
+        }
+        write_entry(entry.table, entry.index, 0);
+        aarch64_asm!("dsb   ishst");
+        tlb::invalidate_removed_va(addr);
+      }
+      addr = match entry_last.checked_add(1) {
+        Some(next) => next,
+        None => break,
+      };
+    }
+  }
+
+  Some(())
+}
+
+/// Call the given function for each valid block and page entry of the translation tables of an address region with
+/// the virtual address and size the entry covers and the raw entry. ``base`` is the virtual address the region starts
+/// at.
+///
+/// # Safety
+/// The translation tables shall not be changed concurrently
+pub(crate) unsafe fn for_each_mapping(
+  config: &MmuConfig,
+  base: usize,
+  f: &mut dyn FnMut(usize, usize, u64),
+) {
+  if !config.root.is_null() {
+    visit_table(
+      config.granule,
+      config.root,
+      config.start_level(),
+      config.root_entries(),
+      base,
+      f,
+    );
+  }
+}
+
+/// Call the given function for each valid block and page entry of the given table and all next level tables it
+/// refers to
+unsafe fn visit_table(
+  granule: Granule,
+  table: *const u64,
+  level: usize,
+  entries: usize,
+  base: usize,
+  f: &mut dyn FnMut(usize, usize, u64),
+) {
+  let shift = granule.level_shift(level);
+  for idx in 0..entries {
+    let entry = read_entry(table, idx);
+    let va = base.wrapping_add(idx << shift);
+    if !is_valid(entry) {
+      continue;
+    }
+    if is_table(level, entry) {
+      let next_table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *const u64;
+      visit_table(
+        granule,
+        next_table,
+        level + 1,
+        granule.table_entries(),
+        va,
+        f,
+      );
+    } else {
+      f(va, 1 << shift, entry);
+    }
+  }
+}
+
+/// Release all translation tables of an address region back to the table memory pool
+///
+/// # Safety
+/// The translation tables shall no longer be used by the MMU
+pub(crate) unsafe fn release_tables(config: &MmuConfig) {
+  if !config.root.is_null() {
+    release_table_tree(
+      config.granule,
+      config.root,
+      config.start_level(),
+      config.root_entries(),
+    );
+  }
+}
+
+/// Check whether the given table and all next level tables it refers to do not contain any valid block or page entry
+///
+/// # Safety
+/// The table need to point to a valid translation table of the given level with ``entries`` entries
+pub(crate) unsafe fn is_table_tree_empty(
+  granule: Granule,
+  table: *const u64,
+  level: usize,
+  entries: usize,
+) -> bool {
+  (0..entries).all(|idx| {
+    let entry = read_entry(table, idx);
+    if is_table(level, entry) {
+      let next_table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *const u64;
+      is_table_tree_empty(granule, next_table, level + 1, granule.table_entries())
+    } else {
+      !is_valid(entry)
+    }
+  })
+}
+
+/// Release the given table and all next level tables it refers to
+pub(crate) unsafe fn release_table_tree(
+  granule: Granule,
+  table: *mut u64,
+  level: usize,
+  entries: usize,
+) {
+  if level < 3 {
+    for idx in 0..entries {
+      let entry = read_entry(table, idx);
+      if is_table(level, entry) {
+        let next_table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+        release_table_tree(granule, next_table, level + 1, granule.table_entries());
+      }
+    }
+  }
+  release_table(table, granule);
+}
+
+/// Read the entry with the given index from a translation table
+///
+/// # Safety
+/// The table need to point to a valid translation table with at least ``index + 1`` entries
+pub(crate) unsafe fn read_entry(table: *const u64, index: usize) -> u64 {
+  read_volatile(table.add(index))
+}
+
+/// Write the entry with the given index of a translation table
+///
+/// # Safety
+/// The table need to point to a valid translation table with at least ``index + 1`` entries
+pub(crate) unsafe fn write_entry(table: *mut u64, index: usize, value: u64) {
+  write_volatile(table.add(index), value);
+  // only the tables of the pool are shadowed, tables provided by the caller (see ``swap_ttbr0``) are not
+  #[cfg(feature = "shadow-tables")]
+  {
+    let offset = (table.add(index) as usize).wrapping_sub(&TABLE_POOL as *const _ as usize);
+    if SHADOW && offset < size_of::<TablePool>() {
+      let shadow = &mut SHADOW_POOL as *mut _ as *mut u64;
+      write_volatile(shadow.add(offset / 8), value);
+    }
+  }
+}
+
+/// A translation table entry is valid if bit 0 of the descriptor is set. The entries referring to the sentinel table
+/// of the upper address region are not considered valid, as they only catch the accesses to unmapped virtual
+/// addresses.
+pub(crate) fn is_valid(entry: u64) -> bool {
+  entry & 0b1 == 0b1 && !ttbr1::is_sentinel(entry)
+}
+
+/// Whether the entry might be cached in the TLB. In contrast to ``is_valid`` this includes the entries referring to
+/// the sentinel table of the upper address region.
+fn is_cached(entry: u64) -> bool {
+  entry & 0b1 == 0b1
+}
+
+/// Whether the entry of a table at the given level refers to a next level table
+pub(crate) fn is_table(level: usize, entry: u64) -> bool {
+  level < 3
+    && is_valid(entry)
+    && TTLB_TABLE::TYPE::from_raw(entry).value() == TTLB_TABLE::TYPE::VALID.value()
+}
+
+unsafe fn is_chunk_used(chunk: usize) -> bool {
+  POOL_USAGE[chunk / 64] & (1 << (chunk % 64)) != 0
+}
+
+/// Serializes the unit tests using the table memory pool, which is shared by all of them
+#[cfg(test)]
+static POOL_TAKEN: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+/// Exclusive access to the table memory pool for a unit test, released once dropped
+#[cfg(test)]
+pub(crate) struct PoolGuard;
+
+/// Wait for exclusive access to the table memory pool and start from an empty pool
+#[cfg(test)]
+pub(crate) fn take_pool() -> PoolGuard {
+  use core::sync::atomic::Ordering;
+  while POOL_TAKEN
+    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+    .is_err()
+  {
+    std::thread::yield_now();
+  }
+  unsafe { reset_pool() };
+  PoolGuard
+}
+
+#[cfg(test)]
+impl Drop for PoolGuard {
+  fn drop(&mut self) {
+    POOL_TAKEN.store(false, core::sync::atomic::Ordering::Release);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{config::MmuInitConfig, ttbr0};
+
+  #[test]
+  fn range_may_end_at_the_end_of_the_address_space() {
+    assert!(!wraps_around(!0xFFF, 0x1000));
+    assert!(!wraps_around(usize::MAX, 0));
+    assert!(wraps_around(!0xFFF, 0x1001));
+    assert!(wraps_around(0x1000, usize::MAX));
+  }
+
+  #[test]
+  fn wrapping_ranges_are_rejected() {
+    let _pool = take_pool();
+    unsafe {
+      ttbr0::setup_translation_tables(0, &MmuInitConfig::new(0x3000_0000, 0x0800_0000));
+      let config = ttbr0::mmu_config();
+      let attributes = MemoryAttributes::normal_cached().raw();
+      assert_eq!(
+        map_range(config, 0x1000, 0x1000, usize::MAX, attributes),
+        None
+      );
+      assert_eq!(map_range(config, 0x1000, !0xFFF, 0x2000, attributes), None);
+      assert_eq!(
+        protect_range(config, 0x1000, usize::MAX, attributes, 0),
+        None
+      );
+      assert_eq!(clear_range(config, 0x1000, usize::MAX), None);
+      // the range is left untouched
+      assert_eq!(translate(config, 0x1000).map(|(pa, _)| pa), Some(0x1000));
+      ttbr0::reset();
+    }
+  }
+}
@@ -0,0 +1,183 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # TLB Maintenance
+//!
+//! Invalidation of cached translations after the translation tables have been changed. As the translation tables are
+//! shared between the cores the invalidation is broadcast to all cores within the inner shareable domain by default.
+//! It can be restricted to the current core with ``set_broadcast`` if the cores do not share translation tables.
+//!
+//! The invalidation of removed mappings can be deferred with ``set_deferred``. The virtual addresses are recorded in a
+//! small buffer instead and invalidated at once with ``flush_pending``. Replaced mappings are always invalidated
+//! immediately as the break-before-make sequence requires the old translation to be flushed before the new entry is
+//! written.
+//!
+//! Enabling and disabling the MMU only changes the translation regime of the current core, so the flush of all cached
+//! translations done in this case is always local to the core.
+//!
+
+use ruspiro_arch_aarch64::register::currentel;
+
+use super::stats;
+
+/// Broadcast the TLB maintenance to all cores of the inner shareable domain
+static mut BROADCAST: bool = true;
+
+/// The number of virtual addresses that can be pending for invalidation before all cached translations are flushed
+const PENDING_SLOTS: usize = 32;
+
+/// Defer the invalidation of removed mappings until ``flush_pending`` is called
+static mut DEFERRED: bool = false;
+
+/// The virtual addresses of the removed mappings whose invalidation is pending
+static mut PENDING: [usize; PENDING_SLOTS] = [0; PENDING_SLOTS];
+
+/// The number of valid entries of ``PENDING``
+static mut PENDING_COUNT: usize = 0;
+
+/// More mappings have been removed than could be recorded, so all cached translations need to be flushed
+static mut PENDING_OVERFLOW: bool = false;
+
+/// Choose whether the TLB maintenance is broadcast to all cores of the inner shareable domain or only done for the
+/// current core
+pub fn set_broadcast(inner_shareable: bool) {
+  unsafe { BROADCAST = inner_shareable };
+}
+
+/// Choose whether the invalidation of removed mappings is deferred until ``flush_pending`` is called
+pub fn set_deferred(deferred: bool) {
+  unsafe { DEFERRED = deferred };
+}
+
+/// Invalidate all cached translations of the given virtual address whose mapping has been removed. If the
+/// invalidation is deferred the address is recorded to be invalidated with the next call to ``flush_pending``.
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub(crate) unsafe fn invalidate_removed_va(va: usize) {
+  if !DEFERRED {
+    invalidate_va(va);
+  } else if PENDING_COUNT < PENDING_SLOTS {
+    PENDING[PENDING_COUNT] = va;
+    PENDING_COUNT += 1;
+  } else {
+    PENDING_OVERFLOW = true;
+  }
+}
+
+/// Invalidate all cached translations of the removed mappings of the virtual address range ``va..va + size``, one
+/// invalidation for each ``step`` bytes. If the invalidation is deferred the addresses are recorded to be invalidated
+/// with the next call to ``flush_pending``.
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub(crate) unsafe fn invalidate_removed_range(va: usize, size: usize, step: usize) {
+  if !DEFERRED {
+    invalidate_range(va, size, step);
+  } else {
+    for addr in (va..va + size).step_by(step) {
+      invalidate_removed_va(addr);
+    }
+  }
+}
+
+/// Invalidate the cached translations of all removed mappings recorded since the last call. If more mappings have been
+/// removed than could be recorded all cached translations are invalidated instead.
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn flush_pending() {
+  if PENDING_OVERFLOW {
+    invalidate_all();
+  } else {
+    for &va in PENDING[..PENDING_COUNT].iter() {
+      invalidate_va(va);
+    }
+  }
+  PENDING_COUNT = 0;
+  PENDING_OVERFLOW = false;
+}
+
+/// Invalidate all cached translations of the given virtual address for the current exception level
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn invalidate_va(va: usize) {
+  issue_va(va);
+  complete();
+}
+
+/// Invalidate all cached translations of the virtual address range ``va..va + size``, one invalidation for each
+/// ``step`` bytes, and wait once for all of them to complete
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub(crate) unsafe fn invalidate_range(va: usize, size: usize, step: usize) {
+  for addr in (va..va + size).step_by(step) {
+    issue_va(addr);
+  }
+  complete();
+}
+
+/// Issue the invalidation of all cached translations of the given virtual address without waiting for it to complete
+unsafe fn issue_va(va: usize) {
+  // the operand contains the bits VA[55:12] of the virtual address to invalidate
+  let operand = (va >> 12) & 0xFFF_FFFF_FFFF;
+  match (currentel::read(currentel::EL::Field).value(), BROADCAST) {
+    (1, true) => aarch64_asm!("tlbi  vaae1is, $0"::"r"(operand)::"volatile"),
+    (1, false) => aarch64_asm!("tlbi  vaae1, $0"::"r"(operand)::"volatile"),
+    (2, true) => aarch64_asm!("tlbi  vae2is, $0"::"r"(operand)::"volatile"),
+    (2, false) => aarch64_asm!("tlbi  vae2, $0"::"r"(operand)::"volatile"),
+    _ => (),
+  }
+}
+
+/// Invalidate all cached non-global translations tagged with the given ASID
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn invalidate_asid(asid: u16) {
+  // the operand contains the ASID in bits [63:48]
+  let operand = (asid as u64) << 48;
+  if BROADCAST {
+    aarch64_asm!("tlbi  aside1is, $0"::"r"(operand)::"volatile");
+  } else {
+    aarch64_asm!("tlbi  aside1, $0"::"r"(operand)::"volatile");
+  }
+  complete();
+}
+
+/// Invalidate all cached translations of the current exception level
+///
+/// # Safety
+/// This is always safe to be called, however, it is marked unsafe as it directly accesses the TLB
+pub unsafe fn invalidate_all() {
+  match (currentel::read(currentel::EL::Field).value(), BROADCAST) {
+    (1, true) => aarch64_asm!("tlbi  vmalle1is"),
+    (1, false) => aarch64_asm!("tlbi  vmalle1"),
+    (2, true) => aarch64_asm!("tlbi  alle2is"),
+    (2, false) => aarch64_asm!("tlbi  alle2"),
+    _ => (),
+  }
+  complete();
+}
+
+/// Wait for the TLB maintenance to complete within the domain it has been issued to
+unsafe fn complete() {
+  stats::update(|stats| stats.tlb_flushes += 1);
+  if BROADCAST {
+    aarch64_asm!(
+      "dsb   ish
+       isb"
+    );
+  } else {
+    aarch64_asm!(
+      "dsb   nsh
+       isb"
+    );
+  }
+}
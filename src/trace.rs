@@ -0,0 +1,96 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Initialization Trace
+//!
+//! With the ``trace`` feature active each stage of the MMU initialization emits a distinct marker to the PL011 UART
+//! located within the peripherals of the configuration that is applied (``MmuInitConfig::peripheral_base``).
+//! The markers are written directly to the UART registers, so they do not depend on any other crate and work with the
+//! MMU being disabled or enabled - the peripherals are always part of the 1:1 mapping. The UART is expected to be
+//! initialized already. Without the ``trace`` feature the markers are compiled out. A ``pre-enable`` marker that is not
+//! followed by the ``post-enable`` marker shows the core hung while the MMU got activated, which typically points to
+//! broken translation tables.
+//!
+//! Independent of the ``trace`` feature the panics of the MMU layer are written the same way to the PL011 UART
+//! configured with ``MmuInitConfig::panic_uart_base``, so they are visible even if the higher level console is not
+//! available.
+//!
+
+use core::ptr::{read_volatile, write_volatile};
+
+#[cfg(feature = "trace")]
+use super::config::PERIPHERAL_BASE;
+
+/// offset of the PL011 UART registers from the start of the peripherals
+#[cfg(feature = "trace")]
+const UART0_OFFSET: usize = 0x20_1000;
+/// offset of the data register of the PL011 UART
+const UART_DR: usize = 0x00;
+/// offset of the flag register of the PL011 UART
+const UART_FR: usize = 0x18;
+/// transmit FIFO full flag of the flag register
+const UART_FR_TXFF: u32 = 1 << 5;
+
+/// base address of the PL011 UART registers the markers are written to, located within the peripherals of the
+/// configuration that is applied
+#[cfg(feature = "trace")]
+static mut UART0_BASE: usize = PERIPHERAL_BASE as usize + UART0_OFFSET;
+
+/// base address of the PL011 UART the panics of the MMU layer are written to
+static mut PANIC_UART_BASE: Option<usize> = None;
+
+/// Emit the given marker to the UART
+#[cfg(feature = "trace")]
+pub fn marker(marker: &str) {
+  write(unsafe { UART0_BASE }, marker);
+}
+
+/// Emit the given marker to the UART - no-op without the ``trace`` feature
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub fn marker(_marker: &str) {}
+
+/// Locate the UART the markers are written to within the peripherals starting at the given base address
+#[cfg(feature = "trace")]
+pub fn set_peripheral_base(base: u32) {
+  unsafe { UART0_BASE = base as usize + UART0_OFFSET };
+}
+
+/// Locate the UART the markers are written to - no-op without the ``trace`` feature
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub fn set_peripheral_base(_base: u32) {}
+
+/// Choose the PL011 UART the panics of the MMU layer are written to
+pub fn set_panic_uart(base: Option<usize>) {
+  unsafe { PANIC_UART_BASE = base };
+}
+
+/// Emit the given panic message to the UART configured for the panics of the MMU layer if there is any
+pub fn emit_panic(message: &str) {
+  if let Some(base) = unsafe { PANIC_UART_BASE } {
+    write(base, "mmu panic: ");
+    write(base, message);
+    write(base, "\r\n");
+  }
+}
+
+/// Emit the given panic message to the configured UART and panic with it
+pub fn fail(message: &str) -> ! {
+  emit_panic(message);
+  panic!("{}", message);
+}
+
+/// Write the given string byte by byte to the PL011 UART at the given base address, polling the transmit FIFO
+fn write(base: usize, data: &str) {
+  for byte in data.bytes() {
+    unsafe {
+      while read_volatile((base + UART_FR) as *const u32) & UART_FR_TXFF != 0 {}
+      write_volatile((base + UART_DR) as *mut u32, byte as u32);
+    }
+  }
+}
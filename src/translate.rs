@@ -0,0 +1,66 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Hardware Address Translation
+//!
+//! Translate a virtual address with the address translation instruction ``AT S1E1R``. The hardware performs the
+//! stage 1 translation with the live TCR_EL1 and TTBRx_EL1 configuration and reports the result in the PAR_EL1
+//! register, which is decoded into the physical address or the fault the translation ran into.
+//!
+
+/// The fault a stage 1 translation with ``translate_el1`` ran into, decoded from the PAR_EL1.FST field. Each fault
+/// carries the translation table level it occurred at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TranslationFault {
+  /// The output address of an entry exceeds the configured physical address size
+  AddressSize(u8),
+  /// The entry of the translation table walk is invalid
+  Translation(u8),
+  /// The access flag of the block or page entry is not set
+  AccessFlag(u8),
+  /// The access permissions do not allow the read access
+  Permission(u8),
+  /// Any other fault, given by the raw fault status code
+  Other(u8),
+}
+
+impl TranslationFault {
+  /// Decode the 6 bit fault status code of the PAR_EL1.FST field
+  fn from_fst(fst: u8) -> Self {
+    let level = fst & 0b11;
+    match fst >> 2 {
+      0b0000 => TranslationFault::AddressSize(level),
+      0b0001 => TranslationFault::Translation(level),
+      0b0010 => TranslationFault::AccessFlag(level),
+      0b0011 => TranslationFault::Permission(level),
+      _ => TranslationFault::Other(fst),
+    }
+  }
+}
+
+/// Translate the virtual address ``va`` into the physical address it is mapped to for a read access from EL1 using the
+/// address translation instruction of the hardware. Unlike ``virt_to_phys`` the translation respects the live
+/// translation regime of the current core, including any change not done by this crate.
+///
+/// # Errors
+/// The ``TranslationFault`` reported in PAR_EL1 if the translation fails.
+pub fn translate_el1(va: usize) -> Result<u64, TranslationFault> {
+  let par: u64;
+  unsafe {
+    aarch64_asm!("at s1e1r, $0"::"r"(va)::"volatile");
+    aarch64_asm!("isb");
+    aarch64_asm!("mrs $0, par_el1" : "=r"(par) ::: "volatile");
+  }
+
+  // PAR_EL1.F tells whether the translation failed
+  if par & 0b1 != 0 {
+    Err(TranslationFault::from_fst(((par >> 1) & 0x3F) as u8))
+  } else {
+    // the output address is given with bits [47:12], the offset into the page is taken from the virtual address
+    Ok((par & 0x0000_FFFF_FFFF_F000) | (va as u64 & 0xFFF))
+  }
+}
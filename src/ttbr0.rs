@@ -10,21 +10,82 @@
 //! Physical address space mapping
 //!
 
-use core::ptr::write_volatile;
-
 use super::{
-  config::{TTLB_BLOCKPAGE, TTLB_TABLE},
-  MmuConfig,
+  attributes::MemoryAttributes,
+  config::{
+    Granule, MmuInitConfig, SECTION_MASK, SECTION_SIZE, TTLB_BLOCKPAGE, TTLB_TABLE, VA_BITS,
+  },
+  error::MmuError,
+  hook::{self, MapOp},
+  stats,
+  tables::{
+    alloc_table, clear_range, is_table, is_valid, lookup, map_range, mark_contiguous,
+    overlaps_table_memory, read_entry, release_table_tree, translate, write_entry, TableEntry,
+  },
+  tlb, trace, MmuConfig,
 };
 
-/// level 1 translation table, each entry covering 1GB of memory
-/// level 2 translation table, each entry covering 2MB of memory
-/// level 3 translation table, each entry covering 4kB of memory
-static mut MMU_CFG: MmuConfig = MmuConfig {
-  ttlb_lvl1: [0; 512],
-  ttlb_lvl2: [0; 1024],
-  //ttlb_lvl3: [0; 2560],
-};
+/// The translation tables of the lower address region
+static mut MMU_CFG: MmuConfig = MmuConfig::new();
+
+/// size of the "device" memory region of the ARM local peripherals like the core mailboxes, that follows right after
+/// the peripherals
+pub(crate) const LOCAL_PERIPHERAL_SIZE: usize = 0x20_0000;
+/// start address of the RAM that exceeds the RAM located below the peripherals
+const HIGH_RAM_START: usize = 0x1_0000_0000;
+
+/// start address of the virtual address range alias mappings are handed out from. This is the upper half of the lower
+/// address region which is never covered by the identity mapping.
+const ALIAS_START: usize = 1 << (VA_BITS - 1);
+/// end address of the virtual address range alias mappings are handed out from
+const ALIAS_END: usize = 1 << VA_BITS;
+
+/// The next free virtual address of the alias range
+static mut NEXT_ALIAS: usize = ALIAS_START;
+
+/// A physical memory region that is mapped 1:1 with the given memory attributes
+#[derive(Copy, Clone)]
+struct IdentityRegion {
+  start: usize,
+  end: usize,
+  attributes: u64,
+}
+
+/// The memory attributes of an address range within the identity mapping
+enum RangeAttributes {
+  /// no part of the range is mapped
+  Unmapped,
+  /// the whole range is mapped with the same memory attributes
+  Uniform(u64),
+  /// the range is only partially mapped or consists of parts with different memory attributes
+  Mixed,
+}
+
+/// The translation table configuration of the lower address region
+///
+/// # Safety
+/// The configuration is only valid once the translation tables are set up
+pub(crate) unsafe fn mmu_config() -> &'static MmuConfig {
+  &MMU_CFG
+}
+
+/// Replace the root table of the lower address region with the given one. The tables are expected to use the same
+/// granule and address range size.
+///
+/// # Safety
+/// The root table need to point to a valid translation table
+pub(crate) unsafe fn set_root(root: *mut u64) {
+  MMU_CFG.root = root;
+}
+
+/// Forget the translation tables of the lower address region and the aliases handed out
+///
+/// # Safety
+/// The MMU shall not use the translation tables of the lower address region any longer.
+pub(crate) unsafe fn reset() {
+  MMU_CFG = MmuConfig::new();
+  NEXT_ALIAS = ALIAS_START;
+}
 
 /// Perform the actual page table configuration to ensure 1:1 memory mapping (virtual -> physical) with the desired
 /// attributes of the lower virtual memory region - typically application space - ranging from
@@ -37,101 +98,690 @@ static mut MMU_CFG: MmuConfig = MmuConfig {
 /// the main core booting up first only. As long as the MMU is not up and running there is no way
 /// to secure access with atomic operations as they require the MMU to be active - otherwise the usage of
 /// atomics will simply hang the core
-pub unsafe fn setup_translation_tables(
-  core: u32,
-  vc_mem_start: u32,
-  vc_mem_size: u32,
-) -> *const u64 {
+pub unsafe fn setup_translation_tables(core: u32, config: &MmuInitConfig) -> *const u64 {
   // initial MMU page table setup only on core 0!
   if core == 0 {
-    // this first attempt provides very huge configuration blocks, meaning we
-    // setup the smallest unit to cover 2Mb blocks of memory sharing the same memory attributes
-
-    let level2_addr_1 = &MMU_CFG.ttlb_lvl2[0] as *const u64;
-    let level2_addr_2 = &MMU_CFG.ttlb_lvl2[512] as *const u64;
-
-    // the entries in level 1 (covering 1GB each) need to point to the next level table
-    // that contains more granular config
-    write_volatile(
-      &mut MMU_CFG.ttlb_lvl1[0] as *mut u64,
-      (TTLB_TABLE::NS::SET
-        | TTLB_TABLE::TYPE::VALID
-        | TTLB_TABLE::ADDR::from_raw(level2_addr_1 as u64))
-      .raw_value(),
-    );
-    write_volatile(
-      &mut MMU_CFG.ttlb_lvl1[1] as *mut u64,
-      (TTLB_TABLE::NS::SET
-        | TTLB_TABLE::TYPE::VALID
-        | TTLB_TABLE::ADDR::from_raw(level2_addr_2 as u64))
-      .raw_value(),
+    let granule = config.tg0;
+    let root =
+      alloc_table(granule).unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+    MMU_CFG = MmuConfig {
+      granule,
+      root,
+      va_bits: VA_BITS,
+      access_flag: config.ttbr0_af.effective(),
+    };
+    // the aliases of a previous run are not part of the new translation tables
+    NEXT_ALIAS = ALIAS_START;
+
+    // the entries of the root table either point to the next level table that contains more granular config or
+    // contain the memory attributes of the 1:1 mapped memory region they cover
+    let layout = identity_layout(config);
+    populate_table(
+      granule,
+      root,
+      MMU_CFG.start_level(),
+      MMU_CFG.root_entries(),
+      0,
+      &layout,
+      config.level1_blocks,
     );
+    if config.trap_null {
+      trap_null_page();
+    }
 
-    // the entries in level 2 (covering 2MB each) contain the specific memory attributes for this memory area
-    // first entries up to an initial fixed address (VideoCore Memory start) covering 2Mb are "normal" memory
-    // get the block that covers the VideoCore memory
-    let vc_start_block = (vc_mem_start >> 21) as usize;
-    let vc_end_block = ((vc_mem_start + vc_mem_size) >> 21) as usize;
-    for i in 0..vc_start_block {
-      // 1:1 memory mapping with it's attributes
-      write_volatile(
-        &mut MMU_CFG.ttlb_lvl2[i],
-        (TTLB_BLOCKPAGE::NS::SET
-          | TTLB_BLOCKPAGE::AF::SET
-          | TTLB_BLOCKPAGE::SH::INNER
-          | TTLB_BLOCKPAGE::MEMATTR::MAIR4
-          | TTLB_BLOCKPAGE::TYPE::BLOCK
-          | TTLB_BLOCKPAGE::ADDR::from_raw((i as u64) << 21))
-        .raw_value(),
-      ); // block entry
+    aarch64_asm!("dsb   ishst");
+  }
+
+  MMU_CFG.root as *const u64
+}
+
+/// Update the identity mapping set up with the ``applied`` configuration to the one of the given configuration while
+/// the MMU stays active. Only the 2MB blocks the memory attributes differ for are re-maintained following the
+/// break-before-make sequence, so the memory the code is running from is not touched as long as it's attributes do not
+/// change. Blocks the VideoCore memory starts or ends within are re-maintained page by page.
+///
+/// # Safety
+/// The translation table granule of both configurations need to be the same and the memory that is re-maintained
+/// shall not be accessed while this is ongoing.
+pub(crate) unsafe fn update_identity_mapping(applied: &MmuInitConfig, config: &MmuInitConfig) {
+  if MMU_CFG.root.is_null() {
+    return;
+  }
+
+  let applied_layout = identity_layout(applied);
+  let layout = identity_layout(config);
+  let end = applied_layout
+    .iter()
+    .chain(layout.iter())
+    .map(|region| region.end)
+    .max()
+    .unwrap_or(0);
+  for start in (0..end).step_by(SECTION_SIZE) {
+    update_identity_range(&applied_layout, &layout, start, SECTION_SIZE);
+  }
+
+  // the first page is maintained apart from the layout
+  let page_size = MMU_CFG.granule.page_size();
+  if config.trap_null {
+    trap_null_page();
+  } else if let (true, RangeAttributes::Uniform(attributes)) =
+    (applied.trap_null, range_attributes(&layout, 0, page_size))
+  {
+    map_range(&MMU_CFG, 0, 0, page_size, attributes)
+      .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+  }
+}
+
+/// Re-maintain the identity mapping of the address range ``start..start + size`` if it's memory attributes differ
+/// between the ``applied`` and the new layout. A range that is not uniformly attributed in the new layout is
+/// re-maintained page by page.
+///
+/// # Safety
+/// The memory that is re-maintained shall not be accessed while this is ongoing.
+unsafe fn update_identity_range(
+  applied: &[IdentityRegion],
+  layout: &[IdentityRegion],
+  start: usize,
+  size: usize,
+) {
+  let end = start + size;
+  match (
+    range_attributes(applied, start, end),
+    range_attributes(layout, start, end),
+  ) {
+    (RangeAttributes::Uniform(previous), RangeAttributes::Uniform(attributes))
+      if previous == attributes => {}
+    (RangeAttributes::Unmapped, RangeAttributes::Unmapped) => {}
+    (_, RangeAttributes::Uniform(attributes)) => {
+      map_range(&MMU_CFG, start, start, size, attributes)
+        .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
     }
+    (_, RangeAttributes::Unmapped) => {
+      clear_range(&MMU_CFG, start, size)
+        .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+    }
+    (_, RangeAttributes::Mixed) => {
+      let page_size = MMU_CFG.granule.page_size();
+      for page in (start..end).step_by(page_size) {
+        update_identity_range(applied, layout, page, page_size);
+      }
+    }
+  }
+}
 
-    // Configure the VC memory region to be non-cacheable from ARM point of view
-    for i in vc_start_block..vc_end_block {
-      // 1:1 memory mapping with it's attributes
-      write_volatile(
-        &mut MMU_CFG.ttlb_lvl2[i],
-        (TTLB_BLOCKPAGE::AF::SET
-          | TTLB_BLOCKPAGE::SH::INNER
-          | TTLB_BLOCKPAGE::MEMATTR::MAIR3
-          | TTLB_BLOCKPAGE::TYPE::BLOCK
-          | TTLB_BLOCKPAGE::ADDR::from_raw((i as u64) << 21))
-        .raw_value(),
-      ); // block entry
+/// Remove the mapping of the first page of the identity mapping, splitting the block containing it into pages
+///
+/// # Safety
+/// The translation tables of the lower address region need to be set up already
+unsafe fn trap_null_page() {
+  clear_range(&MMU_CFG, 0, MMU_CFG.granule.page_size())
+    .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+}
+
+/// Replace the memory attributes of all blocks and pages of the identity mapping set up with the given configuration
+/// that are "normal" memory, leaving the VideoCore and the device memory untouched. The blocks containing the
+/// translation tables keep their attributes as well, as the table walk relies on them. Following the break-before-make
+/// sequence all those entries are invalidated first, the cached translations are flushed at once and the entries are
+/// re-written with the new memory attributes afterwards.
+///
+/// # Safety
+/// While the entries are invalid any access to the "normal" memory faults. The code, it's stack and any data accessed
+/// need to be located outside of the identity mapped "normal" memory.
+pub(crate) unsafe fn remap_normal(config: &MmuInitConfig, attributes: MemoryAttributes) {
+  if MMU_CFG.root.is_null() {
+    return;
+  }
+
+  let layout = identity_layout(config);
+  // the invalid entries keep their output address and type, so they can be made valid again with the new attributes
+  for_each_normal_entry(&layout, &mut |entry| {
+    let value = read_entry(entry.table, entry.index);
+    if is_valid(value) {
+      write_entry(entry.table, entry.index, value & !0b1);
+    }
+  });
+  aarch64_asm!("dsb   ishst");
+  tlb::invalidate_all();
+
+  let keep = (TTLB_BLOCKPAGE::TYPE::from_raw(!0)
+    | TTLB_BLOCKPAGE::ADDR::from_raw(!0)
+    | TTLB_BLOCKPAGE::C::from_raw(!0))
+  .raw_value();
+  let attributes = MMU_CFG.entry_attributes(attributes.raw());
+  for_each_normal_entry(&layout, &mut |entry| {
+    let value = read_entry(entry.table, entry.index);
+    if value != 0 && !is_valid(value) {
+      write_entry(entry.table, entry.index, (value & keep) | attributes | 0b1);
+    }
+  });
+  aarch64_asm!(
+    "dsb   ishst
+     isb"
+  );
+
+  // the hook is only called once the "normal" memory is accessible again
+  let normal = MemoryAttributes::normal_cached().raw();
+  for region in layout.iter().filter(|region| region.attributes == normal) {
+    if region.start < region.end {
+      hook::notify(
+        MapOp::Remap,
+        region.start,
+        region.start,
+        region.end - region.start,
+        attributes,
+      );
+    }
+  }
+}
+
+/// Replace the level 1 entry of the identity mapping covering the 1GB region starting at ``pa`` with a single block
+/// entry using the given memory attributes. The entry is re-maintained following the break-before-make sequence. If
+/// the region has been maintained with a level 2 table before, the table is released afterwards. As the entry covers
+/// a whole 1GB region all cached translations are flushed.
+///
+/// # Safety
+/// The translation tables of the lower address region need to be set up already. While the entry is invalid any
+/// access to the 1GB region faults.
+pub(crate) unsafe fn remap_gb(pa: usize, attributes: MemoryAttributes) -> Result<(), MmuError> {
+  if MMU_CFG.root.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  let granule = MMU_CFG.granule;
+  if !granule.supports_block(1) || MMU_CFG.start_level() != 1 {
+    return Err(MmuError::UnsupportedBlockSize);
+  }
+  let block_size = 1 << granule.level_shift(1);
+  if pa & (block_size - 1) != 0 {
+    return Err(MmuError::unaligned(pa, block_size));
+  }
+  if pa >= ALIAS_START {
+    return Err(MmuError::SizeOverflow);
+  }
+  if overlaps_table_memory(pa, block_size) && !attributes.is_table_walk_compatible() {
+    return Err(MmuError::WouldCorruptTables);
+  }
+
+  let index = pa >> granule.level_shift(1);
+  let previous = read_entry(MMU_CFG.root, index);
+  let value = (TTLB_BLOCKPAGE::TYPE::BLOCK | TTLB_BLOCKPAGE::ADDR::from_raw(pa as u64)).raw_value()
+    | MMU_CFG.entry_attributes(attributes.raw());
+
+  // break-before-make, any block or page of the region may be cached with the previous entry
+  stats::update(|stats| stats.remaps += 1);
+  write_entry(MMU_CFG.root, index, 0);
+  aarch64_asm!("dsb   ishst");
+  tlb::invalidate_all();
+  write_entry(MMU_CFG.root, index, value);
+  aarch64_asm!(
+    "dsb   ishst
+     isb"
+  );
+
+  if is_table(1, previous) {
+    let table = TTLB_TABLE::ADDR::from_raw(previous).raw_value() as *mut u64;
+    release_table_tree(granule, table, 2, granule.table_entries());
+  }
+  hook::notify(MapOp::Remap, pa, pa, block_size, attributes.raw());
+  Ok(())
+}
+
+/// Map the physical memory range ``pa..pa + size`` to a new virtual address of the alias range with the given memory
+/// attributes. The mapping is maintained with pages as required by the alignment of the physical address.
+///
+/// # Safety
+/// The translation tables of the lower address region need to be set up already and no other core maintains them at
+/// the same time.
+pub(crate) unsafe fn map_alias(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<*mut u8, MmuError> {
+  if MMU_CFG.root.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+
+  let page_mask = MMU_CFG.granule.page_size() - 1;
+  let offset = pa & page_mask;
+  let size = offset
+    .checked_add(size)
+    .and_then(|size| size.checked_add(page_mask))
+    .ok_or(MmuError::SizeOverflow)?
+    & !page_mask;
+  let va = NEXT_ALIAS;
+  if size > ALIAS_END - va {
+    return Err(MmuError::address_space_exhausted(
+      size,
+      (ALIAS_END - va) >> MMU_CFG.granule.block_shift(),
+    ));
+  }
+
+  map_range(&MMU_CFG, va, pa & !page_mask, size, attributes.raw())
+    .ok_or(MmuError::TableMemoryExhausted)?;
+  NEXT_ALIAS += size;
+  stats::update(|stats| stats.maps += 1);
+  hook::notify(MapOp::Map, pa & !page_mask, va, size, attributes.raw());
+  Ok((va + offset) as *mut u8)
+}
+
+/// The end of the physical address range the root table entries of the identity mapping cover. This is the end of the
+/// range covered by the last valid root table entry below the alias range or 0 if there is no identity mapping. The
+/// root entries covering the alias range only hold alias mappings and are never part of the identity mapping.
+///
+/// # Safety
+/// The result is only meaningful as long as the identity mapping is not changed concurrently
+pub(crate) unsafe fn identity_end() -> usize {
+  if MMU_CFG.root.is_null() {
+    return 0;
+  }
+
+  let shift = MMU_CFG.granule.level_shift(MMU_CFG.start_level());
+  (0..MMU_CFG.root_entries().min(ALIAS_START >> shift))
+    .rev()
+    .find(|&idx| is_valid(read_entry(MMU_CFG.root, idx)))
+    .map_or(0, |idx| (idx + 1) << shift)
+}
+
+/// Check whether the physical address ``pa`` is covered by a valid block or page entry of the identity mapping. The
+/// addresses of the alias range are never part of the identity mapping.
+///
+/// # Safety
+/// The result is only meaningful as long as the identity mapping is not changed concurrently
+pub(crate) unsafe fn is_identity(pa: usize) -> bool {
+  pa < ALIAS_START && matches!(translate(&MMU_CFG, pa), Some((output, _)) if output == pa)
+}
+
+/// Check whether the physical memory range ``pa..pa + size`` overlaps the "device" memory of the peripherals or the
+/// memory of the VideoCore given by the configuration
+pub(crate) fn overlaps_device_or_vc(
+  config: Option<&MmuInitConfig>,
+  pa: usize,
+  size: usize,
+) -> bool {
+  let end = pa.saturating_add(size.max(1));
+  let overlaps = |start: usize, stop: usize| pa < stop && start < end;
+  let (device_start, _, device_end) =
+    config.map_or(device_regions(&MmuInitConfig::new(0, 0)), device_regions);
+  overlaps(device_start, device_end)
+    || matches!(config, Some(config) if overlaps(
+      config.vc_mem_start as usize,
+      config.vc_mem_start as usize + config.vc_mem_size as usize,
+    ))
+}
+
+/// Determine the indices of the level 1 and the level 2 entry of the identity mapping covering the physical address
+/// ``pa``. Returns ``None`` if the address is not mapped.
+///
+/// # Safety
+/// The result is only meaningful as long as the identity mapping is not changed concurrently
+pub(crate) unsafe fn entry_indices(pa: usize) -> Option<(usize, usize)> {
+  if MMU_CFG.root.is_null() || pa >= ALIAS_START {
+    return None;
+  }
+  let entry = lookup(&MMU_CFG, pa)?;
+  if !is_valid(read_entry(entry.table, entry.index)) {
+    return None;
+  }
+
+  // the translation table walk of the larger granules starts at level 2
+  let granule = MMU_CFG.granule;
+  let (level1, level2_entries) = if MMU_CFG.start_level() == 1 {
+    (
+      (pa >> granule.level_shift(1)) & (MMU_CFG.root_entries() - 1),
+      granule.table_entries(),
+    )
+  } else {
+    (0, MMU_CFG.root_entries())
+  };
+  Some((
+    level1,
+    (pa >> granule.level_shift(2)) & (level2_entries - 1),
+  ))
+}
+
+/// Check whether the physical memory range ``pa..pa + size`` is completely covered by the identity mapping with the
+/// given memory attributes
+///
+/// # Safety
+/// This is safe to be called at any time, however, the result is only meaningful as long as the identity mapping is
+/// not changed concurrently
+pub unsafe fn is_identity_mapped(pa: usize, size: usize, attributes: MemoryAttributes) -> bool {
+  if MMU_CFG.root.is_null() {
+    return false;
+  }
+
+  let end = pa + size;
+  let mut addr = pa;
+  while addr < end {
+    let entry = match lookup(&MMU_CFG, addr) {
+      Some(entry) => entry,
+      None => return false,
+    };
+    let value = read_entry(entry.table, entry.index);
+    if !is_valid(value) || MemoryAttributes::from_raw(value) != attributes {
+      return false;
     }
+    let entry_size = 1 << MMU_CFG.granule.level_shift(entry.level);
+    addr = (addr & !(entry_size - 1)) + entry_size;
+  }
 
+  true
+}
+
+/// Provide the start address of the peripherals, the start address of the ARM local peripherals and the end address of
+/// the "device" memory region of the given configuration
+fn device_regions(config: &MmuInitConfig) -> (usize, usize, usize) {
+  let device_start = config.peripheral_base as usize;
+  let local_device_start = device_start + config.peripheral_size as usize;
+  (
+    device_start,
+    local_device_start,
+    local_device_start + LOCAL_PERIPHERAL_SIZE,
+  )
+}
+
+/// Provide the memory regions of the identity mapping. The first entries up to the VideoCore memory start are "normal"
+/// memory, followed by the VideoCore memory that is non-cacheable from ARM point of view and "normal" memory again up
+/// to the "device" memory the peripherals and the ARM local peripherals like the core mailboxes are located at. The RAM
+/// exceeding the first 4GB is "normal" memory as well.
+fn identity_layout(config: &MmuInitConfig) -> [IdentityRegion; 6] {
+  let (device_start, local_device_start, device_end) = device_regions(config);
+  // the VideoCore memory is maintained with the page granularity of the translation tables, so only the blocks the
+  // VideoCore memory starts or ends within are split into pages. A page only partially used by the VideoCore is
+  // non-cacheable as a whole.
+  let page_mask = config.tg0.page_size() - 1;
+  let vc_start = config.vc_mem_start as usize & !page_mask;
+  let vc_end =
+    (config.vc_mem_start as usize + config.vc_mem_size as usize + page_mask) & !page_mask;
+  // the first block contains the exception vectors and the early boot code, so it is always "normal" memory even if
+  // the VideoCore memory is reported to start within this block
+  let vc_start = vc_start.clamp(SECTION_SIZE, device_start);
+  let vc_end = vc_end.min(device_start).max(vc_start);
+
+  let normal = MemoryAttributes::normal_cached().raw();
+  // the memory is only mapped within the requested range, extended to the 2MB blocks it covers
+  let (range_start, range_end) = config
+    .identity_range
+    .as_ref()
+    .map_or((0, usize::MAX), |range| {
+      (
+        range.start & !SECTION_MASK,
+        (range.end + SECTION_MASK) & !SECTION_MASK,
+      )
+    });
+  let clip = |start: usize, end: usize| {
+    let start = start.max(range_start);
+    (start, end.min(range_end).max(start))
+  };
+  // the RAM below the peripherals ends at the peripherals, the RAM exceeding it continues at 4GB
+  let low_ram_end = config.ram_size.min(device_start);
+  let (normal_start, normal_end) = clip(0, vc_start.min(low_ram_end));
+  let (vc_start, vc_end) = clip(vc_start, vc_end);
+  let (upper_start, upper_end) = clip(vc_end, low_ram_end);
+  let (high_start, high_end) = clip(HIGH_RAM_START, config.ram_size);
+
+  [
+    IdentityRegion {
+      start: normal_start,
+      end: normal_end,
+      attributes: normal,
+    },
+    // Configure the VC memory region to be non-cacheable from ARM point of view
+    IdentityRegion {
+      start: vc_start,
+      end: vc_end,
+      attributes: MemoryAttributes::normal_uncached().raw(),
+    },
     // if there is a memory block left after VC memory up to the device memory
     // maintain this area as normal memory
-    for i in vc_end_block..504 {
-      // 1:1 memory mapping with it's attributes
-      write_volatile(
-        &mut MMU_CFG.ttlb_lvl2[i],
-        (TTLB_BLOCKPAGE::NS::SET
-          | TTLB_BLOCKPAGE::AF::SET
-          | TTLB_BLOCKPAGE::SH::INNER
-          | TTLB_BLOCKPAGE::MEMATTR::MAIR4
-          | TTLB_BLOCKPAGE::TYPE::BLOCK
-          | TTLB_BLOCKPAGE::ADDR::from_raw((i as u64) << 21))
-        .raw_value(),
-      ); // block entry
+    IdentityRegion {
+      start: upper_start,
+      end: upper_end,
+      attributes: normal,
+    },
+    IdentityRegion {
+      start: high_start,
+      end: high_end,
+      attributes: normal,
+    },
+    // the peripherals and the ARM local peripherals are "device" memory, they are mapped regardless of the identity
+    // range
+    IdentityRegion {
+      start: device_start,
+      end: local_device_start,
+      attributes: MemoryAttributes::device_identity(config.peripheral_ordering).raw(),
+    },
+    IdentityRegion {
+      start: local_device_start,
+      end: device_end,
+      attributes: MemoryAttributes::device_identity(config.local_peripheral_ordering).raw(),
+    },
+  ]
+}
+
+/// Call the given function for each block, page or invalid entry covering the "normal" memory regions of the layout,
+/// except those covering the translation table memory
+unsafe fn for_each_normal_entry(layout: &[IdentityRegion], f: &mut dyn FnMut(&TableEntry)) {
+  let normal = MemoryAttributes::normal_cached().raw();
+  for region in layout.iter().filter(|region| region.attributes == normal) {
+    let mut addr = region.start;
+    while addr < region.end {
+      // the RAM is limited to the virtual address range of the identity mapping by ``validate_config``
+      let entry = match lookup(&MMU_CFG, addr) {
+        Some(entry) => entry,
+        None => break,
+      };
+      let entry_size = 1 << MMU_CFG.granule.level_shift(entry.level);
+      let entry_start = addr & !(entry_size - 1);
+      if !overlaps_table_memory(entry_start, entry_size) {
+        f(&entry);
+      }
+      addr = entry_start + entry_size;
     }
+  }
+}
 
-    // entries from 0x3F00_0000 to 0x4020_0000 are "device" memory
-    for i in 504..513 {
+/// Determine the memory attributes of the address range ``start..end`` within the identity mapping
+fn range_attributes(layout: &[IdentityRegion], start: usize, end: usize) -> RangeAttributes {
+  layout
+    .iter()
+    .find(|region| region.start < region.end && region.start < end && start < region.end)
+    .map_or(RangeAttributes::Unmapped, |region| {
+      if region.start <= start && end <= region.end {
+        RangeAttributes::Uniform(region.attributes)
+      } else {
+        RangeAttributes::Mixed
+      }
+    })
+}
+
+/// Populate the entries of the translation table at the given level covering the address range starting at ``base``.
+/// Uniformly attributed ranges are mapped with block entries at level 2 and page entries at level 3. With
+/// ``level1_blocks`` they are mapped with block entries at level 1 as well if the granule supports them. Ranges that
+/// need a more granular configuration are pointing to a next level table.
+unsafe fn populate_table(
+  granule: Granule,
+  table: *mut u64,
+  level: usize,
+  entries: usize,
+  base: usize,
+  layout: &[IdentityRegion],
+  level1_blocks: bool,
+) {
+  let shift = granule.level_shift(level);
+  let is_block_level = level == 2 || (level == 1 && level1_blocks && granule.supports_block(1));
+  for idx in 0..entries {
+    let start = base + (idx << shift);
+    let end = start + (1 << shift);
+    match range_attributes(layout, start, end) {
+      RangeAttributes::Unmapped => (),
       // 1:1 memory mapping with it's attributes
-      write_volatile(
-        &mut MMU_CFG.ttlb_lvl2[i],
-        (TTLB_BLOCKPAGE::AF::SET
-          | TTLB_BLOCKPAGE::SH::INNER
-          | TTLB_BLOCKPAGE::MEMATTR::MAIR0
-          | TTLB_BLOCKPAGE::TYPE::BLOCK
-          | TTLB_BLOCKPAGE::ADDR::from_raw((i as u64) << 21))
-        .raw_value(),
-      ); // block entry
+      RangeAttributes::Uniform(attributes) if level == 3 => write_entry(
+        table,
+        idx,
+        MMU_CFG.entry_attributes(attributes)
+          | (TTLB_BLOCKPAGE::TYPE::PAGE | TTLB_BLOCKPAGE::ADDR::from_raw(start as u64)).raw_value(),
+      ), // page entry
+      RangeAttributes::Uniform(attributes) if is_block_level => write_entry(
+        table,
+        idx,
+        MMU_CFG.entry_attributes(attributes)
+          | (TTLB_BLOCKPAGE::TYPE::BLOCK | TTLB_BLOCKPAGE::ADDR::from_raw(start as u64))
+            .raw_value(),
+      ), // block entry
+      _ if level < 3 => {
+        let next_table =
+          alloc_table(granule).unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+        write_entry(
+          table,
+          idx,
+          (TTLB_TABLE::NS::SET
+            | TTLB_TABLE::TYPE::VALID
+            | TTLB_TABLE::ADDR::from_raw(next_table as u64))
+          .raw_value(),
+        ); // table entry
+        populate_table(
+          granule,
+          next_table,
+          level + 1,
+          granule.table_entries(),
+          start,
+          layout,
+          level1_blocks,
+        );
+        if level + 1 == 3 {
+          mark_contiguous(granule, next_table);
+        }
+      }
+      // as the memory attributes are maintained with page granularity a page is always uniformly attributed
+      _ => (),
     }
+  }
+}
 
-    llvm_asm!("dsb   ishst");
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::PERIPHERAL_BASE;
+  use crate::tables::take_pool;
+
+  /// The memory attributes the layout maps the given physical address with
+  fn attributes_at(layout: &[IdentityRegion], pa: usize) -> Option<u64> {
+    layout
+      .iter()
+      .find(|region| region.start <= pa && pa < region.end)
+      .map(|region| region.attributes)
   }
 
-  &MMU_CFG.ttlb_lvl1[0] as *const u64
+  #[test]
+  fn vc_memory_within_first_block_keeps_it_normal() {
+    let layout = identity_layout(&MmuInitConfig::new(0x10_0000, 0x3000_0000));
+    let normal = Some(MemoryAttributes::normal_cached().raw());
+    let uncached = Some(MemoryAttributes::normal_uncached().raw());
+
+    assert_eq!(attributes_at(&layout, 0), normal);
+    assert_eq!(attributes_at(&layout, 0x10_0000), normal);
+    assert_eq!(attributes_at(&layout, SECTION_SIZE - 1), normal);
+    assert_eq!(attributes_at(&layout, SECTION_SIZE), uncached);
+    assert_eq!(attributes_at(&layout, 0x3010_0000 - 1), uncached);
+    assert_eq!(attributes_at(&layout, 0x3010_0000), normal);
+  }
+
+  #[test]
+  fn unaligned_vc_memory_is_maintained_with_page_granularity() {
+    let normal = Some(MemoryAttributes::normal_cached().raw());
+    let uncached = Some(MemoryAttributes::normal_uncached().raw());
+    let page_size = Granule::_4KB.page_size();
+    // (VideoCore start, VideoCore size, first uncached page, first normal page after the VideoCore memory)
+    let cases = [
+      (0x3B40_1000, 0x03BF_F000, 0x3B40_1000, 0x3F00_0000),
+      (0x3B40_0800, 0x400, 0x3B40_0000, 0x3B40_1000),
+      (0x3B5F_F000, 0x2000, 0x3B5F_F000, 0x3B60_1000),
+      (0x2000_0FFF, 0x1001, 0x2000_0000, 0x2000_2000),
+      (0x0020_0000, 0x0030_0800, 0x0020_0000, 0x0050_1000),
+    ];
+    for &(vc_start, vc_size, uncached_start, normal_start) in cases.iter() {
+      let layout = identity_layout(&MmuInitConfig::new(vc_start, vc_size));
+      assert_eq!(attributes_at(&layout, uncached_start - page_size), normal);
+      assert_eq!(attributes_at(&layout, uncached_start), uncached);
+      assert_eq!(attributes_at(&layout, normal_start - page_size), uncached);
+      if normal_start < PERIPHERAL_BASE as usize {
+        assert_eq!(attributes_at(&layout, normal_start), normal);
+      }
+    }
+  }
+
+  #[test]
+  fn identity_tables_map_every_address_class() {
+    let _pool = take_pool();
+    // the memory split of a Raspberry Pi 3 with RAM left between the VideoCore memory and the peripherals
+    let config = MmuInitConfig::new(0x3000_0000, 0x0800_0000);
+    unsafe { setup_translation_tables(0, &config) };
+
+    let inner = TTLB_BLOCKPAGE::SH::INNER.value();
+    // (address, MAIR index, shareability)
+    let samples = [
+      (0x8_0000, 4, inner),    // low RAM
+      (0x3400_0000, 3, inner), // VideoCore memory
+      (0x3A00_0000, 4, inner), // RAM following the VideoCore memory
+      (0x3F20_1000, 0, inner), // peripherals
+      (0x4000_0000, 0, inner), // ARM local peripherals
+    ];
+    for &(va, mair_index, shareability) in samples.iter() {
+      let entry = unsafe { lookup(&MMU_CFG, va) }.expect("address outside of the lower region");
+      let value = unsafe { read_entry(entry.table, entry.index) };
+      assert!(is_valid(value), "{:#x} is not mapped", va);
+      assert_eq!(
+        unsafe { translate(&MMU_CFG, va) }.map(|(pa, _)| pa),
+        Some(va)
+      );
+      assert_eq!(MemoryAttributes::from_raw(value).mair_index(), mair_index);
+      assert_eq!(TTLB_BLOCKPAGE::SH::from_raw(value).value(), shareability);
+    }
+    unsafe { reset() };
+  }
+
+  #[test]
+  fn identity_end_ignores_alias_mappings() {
+    let _pool = take_pool();
+    let config = MmuInitConfig::new(0x3000_0000, 0x0800_0000);
+    unsafe { setup_translation_tables(0, &config) };
+    let end = unsafe { identity_end() };
+    assert!(end > 0 && end <= ALIAS_START);
+
+    let alias = unsafe { map_alias(0x8_0000, 0x1000, MemoryAttributes::normal_cached()) }
+      .expect("alias mapped");
+    assert!(alias as usize >= ALIAS_START);
+    assert_eq!(unsafe { identity_end() }, end);
+    unsafe { reset() };
+  }
+
+  #[test]
+  fn uniform_gigabyte_is_mapped_with_a_single_level1_block() {
+    let _pool = take_pool();
+    // 8GB of RAM, the RAM above 4GB is uniformly attributed
+    let config = MmuInitConfig {
+      ram_size: 0x2_0000_0000,
+      level1_blocks: true,
+      ..MmuInitConfig::new(0x3000_0000, 0x0800_0000)
+    };
+    unsafe { setup_translation_tables(0, &config) };
+
+    let gigabyte = 0x4000_0000;
+    for &block in [HIGH_RAM_START, HIGH_RAM_START + 3 * gigabyte].iter() {
+      let entry = unsafe { lookup(&MMU_CFG, block + 0x1234_5000) }
+        .expect("address outside of the lower region");
+      let value = unsafe { read_entry(entry.table, entry.index) };
+      assert_eq!(entry.level, 1);
+      assert!(is_valid(value) && !is_table(1, value));
+      assert_eq!(
+        TTLB_BLOCKPAGE::ADDR::from_raw(value).raw_value() as usize,
+        block
+      );
+      assert_eq!(entry.table, unsafe { MMU_CFG.root });
+    }
+    // the first gigabyte contains the VideoCore memory, so it is split further
+    let entry = unsafe { lookup(&MMU_CFG, 0x8_0000) }.expect("address outside of the lower region");
+    assert!(entry.level > 1);
+    unsafe { reset() };
+  }
 }
@@ -10,19 +10,67 @@
 //! Virtual address space mapping
 //!
 
-use core::ptr::write_volatile;
-
-use super::{config::TTLB_TABLE, MmuConfig};
+use core::{
+  ptr::{null_mut, write_bytes},
+  sync::atomic::{AtomicBool, Ordering},
+};
 
-/// level 1 translation table, each entry covering 1GB of memory
-/// level 2 translation table, each entry covering 2MB of memory
-/// level 3 translation table, each entry covering 4kB of memory
-static mut MMU_CFG: MmuConfig = MmuConfig {
-  ttlb_lvl1: [0; 512],
-  ttlb_lvl2: [0; 1024],
-  //ttlb_lvl3: [0; 2560],
+use super::{
+  attributes::MemoryAttributes,
+  config::{AccessFlag, Granule, MmuInitConfig, TTLB_BLOCKPAGE, TTLB_TABLE, VA_BITS},
+  el1,
+  error::MmuError,
+  hook::{self, MapOp},
+  stats,
+  tables::{
+    alloc_table, clear_range, for_each_mapping, is_table, is_table_tree_empty, is_valid, map_range,
+    read_entry, release_table_tree, release_tables, translate, write_entry,
+  },
+  tlb, trace, MapPlan, MmuConfig,
 };
 
+/// The translation tables of the upper address region
+static mut MMU_CFG: MmuConfig = MmuConfig::new();
+
+/// The level 2 table the virtual address blocks are maintained in. It covers the top most part of the virtual
+/// address range
+static mut BLOCK_TABLE: *mut u64 = null_mut();
+
+/// The table entry referring to the level 3 table mapping each page to the "poison" page. Unmapped entries of the
+/// block table are set to this entry if the sentinel mapping is configured, otherwise this is 0.
+static mut SENTINEL_ENTRY: u64 = 0;
+
+/// The configuration the translation tables are set up with on the first mapping if their setup has been deferred
+/// with ``MmuInitConfig::lazy_ttbr1``
+static mut DEFERRED_SETUP: Option<MmuInitConfig> = None;
+
+/// Serializes the deferred translation table setup between the cores. The MMU is active once the tables are set up
+/// lazily, so atomics are usable at this point.
+static SETUP_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// The maximum number of level 2 blocks a single mapping is allowed to use
+static mut MAX_BLOCKS_PER_MAP: usize = usize::MAX;
+
+/// The translation table configuration of the upper address region
+///
+/// # Safety
+/// The configuration is only valid once the translation tables are set up
+pub(crate) unsafe fn mmu_config() -> &'static MmuConfig {
+  &MMU_CFG
+}
+
+/// Forget the translation tables of the upper address region, including a deferred setup
+///
+/// # Safety
+/// The MMU shall not use the translation tables of the upper address region any longer.
+pub(crate) unsafe fn reset() {
+  MMU_CFG = MmuConfig::new();
+  BLOCK_TABLE = null_mut();
+  SENTINEL_ENTRY = 0;
+  DEFERRED_SETUP = None;
+  stats::update(|stats| stats.va_blocks_used = 0);
+}
+
 /// Perform the actual page table configuration to ensure 1:1 memory mapping (virtual -> physical) with the desired
 /// attributes of the lower virtual memory region - typically application space - ranging from
 /// 0xFFFF_FF80_0000_0000 to 0xFFFF_FFFF_FFFFF_FFFF. The upper boundry is given by the SCTLR_EL1-T1SZ register
@@ -34,76 +82,666 @@ static mut MMU_CFG: MmuConfig = MmuConfig {
 /// the main core booting up first only. As long as the MMU is not up and running there is no way
 /// to secure access with atomic operations as they require the MMU to be active - otherwise the usage of
 /// atomics will simply hang the core
-pub unsafe fn setup_translation_tables(core: u32) -> *const u64 {
+pub unsafe fn setup_translation_tables(core: u32, config: &MmuInitConfig) -> *const u64 {
   // initial MMU page table setup only on core 0!
   if core == 0 {
-    // The TTBR1 setting starts with an initial configuartion of valid entries in level 1 covering 1 GB memory space
-    // each. The VA mapping will start at the lowest possible address and works forward while handing out virtual
-    // addresses. The VA are configured 2MB block wise in level 2 only. Even if the mapped physical memory is not
-    // requiring this.
-    // this is the address that is stored in th TTBR1 register. From here the GB sized index starts.
-    // so maintaining one entry that could cover 1GB starting at 0xFFFF_FFFF_FFFF_FFFF. This entry is a table
-    // entry and point to the table where the block configuration is stored, each block covering 2MB of memory
-    let level2_addr = &MMU_CFG.ttlb_lvl2[0] as *const u64;
-    write_volatile(
-      &mut MMU_CFG.ttlb_lvl1[511] as *mut u64,
-      (TTLB_TABLE::NS::SET | TTLB_TABLE::TYPE::VALID).raw_value() | (level2_addr as u64),
-    );
+    // the sentinel table is shared by all unmapped blocks and needs to be available before the block table is set up
+    SENTINEL_ENTRY = 0;
+    if let Some(poison) = config.ttbr1_sentinel {
+      SENTINEL_ENTRY = build_sentinel(config.tg1, poison)
+        .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+    }
+    let max_blocks_per_map = config.max_blocks_per_map;
+    let (config, block_table) = build_window(config.tg1, VA_BITS, config.ttbr1_af.effective())
+      .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+    MMU_CFG = config;
+    BLOCK_TABLE = block_table;
+    MAX_BLOCKS_PER_MAP = max_blocks_per_map;
+    // the new translation tables do not contain any virtual address block of a previous setup
+    stats::update(|stats| stats.va_blocks_used = 0);
 
     // we will not maintain any block entry at the beginning as those are maintained when memory mapping
     // happens and a virtual address is required to be mapped to a physical one with specific memory
     // attributes. As the block entries are all invalid at the beginning any memory access would lead to a access
-    // fault
-    llvm_asm!("dsb   ishst");
+    // fault - or read the "poison" page if the sentinel mapping is configured
+    aarch64_asm!("dsb   ishst");
+  }
+
+  MMU_CFG.root as *const u64
+}
+
+/// Defer the setup of the translation tables of the upper address region to the first mapping. Returns the root table
+/// if another core has set up the tables already, otherwise a null pointer.
+///
+/// # Safety
+/// Same as for ``setup_translation_tables``.
+pub(crate) unsafe fn defer_translation_tables(core: u32, config: &MmuInitConfig) -> *const u64 {
+  if core == 0 {
+    MMU_CFG = MmuConfig::new();
+    BLOCK_TABLE = null_mut();
+    DEFERRED_SETUP = Some(config.clone());
+  }
+
+  MMU_CFG.root as *const u64
+}
+
+/// Set up the translation tables of the upper address region if their setup has been deferred and enable the table
+/// walks of the upper address region on the calling core if they are still disabled.
+///
+/// # Safety
+/// The MMU need to be active.
+unsafe fn ensure_translation_tables() {
+  while SETUP_LOCK
+    .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+    .is_err()
+  {}
+  if let Some(config) = DEFERRED_SETUP.take() {
+    setup_translation_tables(0, &config);
+  }
+  SETUP_LOCK.store(false, Ordering::Release);
+
+  // without any translation tables the table walks stay disabled
+  if !MMU_CFG.root.is_null() {
+    el1::enable_ttbr1(MMU_CFG.root as u64);
+  }
+}
+
+/// Rebuild the translation tables of the upper address region for a ``va_bits`` wide virtual address range. All
+/// existing mappings are migrated to the new translation tables. The new tables become the current ones and the
+/// previous configuration is returned to be released once the MMU does no longer use it.
+///
+/// # Safety
+/// The translation tables need to be set up already and no other core maintains them at the same time.
+/// # Errors
+/// ``MmuError::InvalidWindowSize`` if the address range does not cover at least one level 2 table,
+/// ``MmuError::MappingOutsideWindow`` if any existing mapping is not covered by the new address range and
+/// ``MmuError::TableMemoryExhausted`` if there is no memory left for the new translation tables.
+pub(crate) unsafe fn resize_window(va_bits: usize) -> Result<MmuConfig, MmuError> {
+  if MMU_CFG.root.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  let granule = MMU_CFG.granule;
+  if va_bits <= granule.block_shift() {
+    return Err(MmuError::InvalidWindowSize);
+  }
+
+  // all existing mappings need to be covered by the new address range
+  let window_start = !0 << va_bits;
+  let mut fits = true;
+  for_each_mapping(&MMU_CFG, !0 << MMU_CFG.va_bits, &mut |va, _, _| {
+    fits &= va >= window_start
+  });
+  if !fits {
+    return Err(MmuError::MappingOutsideWindow);
+  }
+
+  let (config, block_table) =
+    build_window(granule, va_bits, MMU_CFG.access_flag).ok_or(MmuError::TableMemoryExhausted)?;
+  let mut migrated = Some(());
+  for_each_mapping(&MMU_CFG, !0 << MMU_CFG.va_bits, &mut |va, size, entry| {
+    if migrated.is_some() {
+      migrated = map_range(
+        &config,
+        va,
+        TTLB_BLOCKPAGE::ADDR::from_raw(entry).raw_value() as usize,
+        size,
+        MemoryAttributes::from_raw(entry).raw(),
+      );
+    }
+  });
+  if migrated.is_none() {
+    release_tables(&config);
+    return Err(MmuError::TableMemoryExhausted);
   }
 
-  &MMU_CFG.ttlb_lvl1[0] as *const u64
+  let previous = MMU_CFG;
+  MMU_CFG = config;
+  BLOCK_TABLE = block_table;
+  Ok(previous)
+}
+
+/// Allocate the root table for a ``va_bits`` wide upper address region and chain it's top most entry down to the level
+/// 2 table virtual address blocks are maintained in. Returns the configuration of the region together with the level
+/// 2 table or ``None`` if there is no memory left for the tables.
+unsafe fn build_window(
+  granule: Granule,
+  va_bits: usize,
+  access_flag: AccessFlag,
+) -> Option<(MmuConfig, *mut u64)> {
+  let root = alloc_table(granule)?;
+  let config = MmuConfig {
+    granule,
+    root,
+    va_bits,
+    access_flag,
+  };
+  // The TTBR1 setting starts with an initial configuartion of a valid table entry for the top most entries of each
+  // table level until level 2 is reached. The VA mapping will start at the lowest possible address of this level 2
+  // table and works forward while handing out virtual addresses. The VA are configured block wise in level 2 only.
+  // Even if the mapped physical memory is not requiring this.
+  // With a 4kB granule this is one entry in level 1 that covers 1GB at the top of the address range and points to
+  // the table where the block configuration is stored, each block covering 2MB of memory
+  let mut table = root;
+  let mut entries = config.root_entries();
+  for _ in config.start_level()..2 {
+    let next_table = match alloc_table(granule) {
+      Some(next_table) => next_table,
+      None => {
+        release_tables(&config);
+        return None;
+      }
+    };
+    write_entry(
+      table,
+      entries - 1,
+      (TTLB_TABLE::NS::SET | TTLB_TABLE::TYPE::VALID).raw_value() | (next_table as u64),
+    );
+    table = next_table;
+    entries = granule.table_entries();
+  }
+
+  // without any mapping all blocks are caught by the sentinel mapping if there is any
+  if SENTINEL_ENTRY != 0 {
+    for idx in 0..block_entries(&config) {
+      write_entry(table, idx, SENTINEL_ENTRY);
+    }
+  }
+
+  Some((config, table))
+}
+
+/// Fill the "poison" page containing the given physical address with 0xDE and provide the table entry referring to a
+/// new level 3 table that maps each of it's pages read-only to the "poison" page. Returns ``None`` if there is no
+/// memory left for the table.
+unsafe fn build_sentinel(granule: Granule, poison: usize) -> Option<u64> {
+  let page = poison & !(granule.page_size() - 1);
+  write_bytes(page as *mut u8, 0xDE, granule.page_size());
+
+  let table = alloc_table(granule)?;
+  let value = (TTLB_BLOCKPAGE::NS::SET
+    | TTLB_BLOCKPAGE::AF::SET
+    | TTLB_BLOCKPAGE::SH::INNER
+    | TTLB_BLOCKPAGE::MEMATTR::MAIR4
+    | TTLB_BLOCKPAGE::AP::RO_EL1
+    | TTLB_BLOCKPAGE::PXN::with_value(1)
+    | TTLB_BLOCKPAGE::XN::with_value(1)
+    | TTLB_BLOCKPAGE::TYPE::PAGE
+    | TTLB_BLOCKPAGE::ADDR::from_raw(page as u64))
+  .raw_value();
+  for idx in 0..granule.table_entries() {
+    write_entry(table, idx, value);
+  }
+
+  Some(
+    (TTLB_TABLE::NS::SET | TTLB_TABLE::TYPE::VALID | TTLB_TABLE::ADDR::from_raw(table as u64))
+      .raw_value(),
+  )
+}
+
+/// Whether the given entry of the block table refers to the sentinel table
+pub(crate) fn is_sentinel(entry: u64) -> bool {
+  unsafe { SENTINEL_ENTRY != 0 && entry == SENTINEL_ENTRY }
 }
 
 /// Maintain the TTBR1 translation table pages to provide the virtual address and it's occupied space with the proper
-/// memory attributes.
+/// memory attributes. The virtual address provided is aligned to ``align`` bytes, which is a power of two. Only the
+/// pages covering the memory are mapped, a level 2 block is only mapped as a whole if the memory covers it completely.
+/// The pages of a block that are not used by the memory stay invalid and may be used by further mappings.
 /// # Safety
 /// This is safe if the address given has been returned by `alloc::alloc(...)` function and spans the size passed.
-/// It will panic if the TTBR1 configuration does not allow to maintain any further VA address range
-/// # TODO
-/// actually it maintains a whole 2MB block for any size given. This is quite wastefull and should be changed to do
-/// page size maintenance incorporating the number of pages to be configured based on the size given
-pub unsafe fn maintain_pages(origin: *mut u8, _size: usize, attributes: u64) -> *mut u8 {
-  // page maintenance is done at the beginning on 2MB block level only. This is quite ok as
-  // we have plenty of virtual memory we can map to physical one. So even the mapped memory falls into the same
-  // physical 2MB region we can use a different 2MB virtual block and virtual address from this block.
-  // This is actually wasting lot's of virtual address space and table entries but for the time beeing we do not
-  // expect many regions to be maintained.
-
-  // 1. find the next free block in the page table
-  let block_entry = MMU_CFG
-    .ttlb_lvl2
-    .iter_mut()
-    .enumerate()
-    .find(|(_, entry)| **entry == 0);
-
-  if let Some((idx, entry)) = block_entry {
-    // we found a block entry we can use
-    // maintain the entry in the translation table
-    let tlb_value = 0b1 << 63
-                | attributes // memory attributes
-                | ((origin as u64) & !0x1F_FFFF) // physical block start address 
-                | 1 << 10 // access flag
-                | 0b01;
-    write_volatile(&mut *entry, tlb_value);
-    // once the table has been updated we need to invalidate this entry
-    let entry_addr = entry as *const u64 as usize;
-    llvm_asm!("dsb   ishst
-                dsb   ish
-                isb
-                dc civac, $0"::"r"(entry_addr)::"volatile");
-    // calculate the virtual address for this entry based on the current block we are using
-    let mut va = 0xFFFF_FFFF_FFFF_FFFF - (((512 - idx) << 21) - 1);
-    va |= origin as usize & 0x1F_FFFF;
-
-    va as *mut u8
+/// # Errors
+/// ``MmuError::RequestTooLarge`` if the memory spans more level 2 blocks than a single mapping is allowed to use,
+/// ``MmuError::AlignmentUnsatisfiable`` if there is no free virtual address block with the requested alignment,
+/// ``MmuError::AddressSpaceExhausted`` if there is no free virtual address block left,
+/// ``MmuError::NoVirtualMapping`` if the translation tables are not set up and ``MmuError::TableMemoryExhausted`` if
+/// there is no memory left for the page tables
+pub unsafe fn maintain_pages(
+  origin: *mut u8,
+  size: usize,
+  attributes: u64,
+  align: usize,
+) -> Result<*mut u8, MmuError> {
+  // the translation tables might be set up with the first mapping only
+  ensure_translation_tables();
+  let plan = plan_pages(origin, size, align)?;
+
+  // the pages keep their offset into the block, so the memory is contiguous in both address spaces
+  let (pa, span) = page_span(origin as usize, size);
+  let va = plan.va - (origin as usize - pa);
+  let claimed = plan
+    .block_entries
+    .clone()
+    .filter(|&idx| !is_valid(read_entry(BLOCK_TABLE, idx)))
+    .count();
+  // the sentinel mapping of the blocks is replaced following the break-before-make sequence
+  if map_range(&MMU_CFG, va, pa, span, attributes).is_none() {
+    clear_pages(va, span);
+    return Err(MmuError::TableMemoryExhausted);
+  }
+
+  stats::update(|stats| stats.maps += 1);
+  stats::claim_va_blocks(claimed as isize);
+  hook::notify(MapOp::Map, origin as usize, plan.va, size, attributes);
+
+  // the virtual address handed out need to be covered by the block that has just been claimed
+  if cfg!(debug_assertions) {
+    let idx = plan.block_entries.start;
+    let va_idx = va_to_block_index(&MMU_CFG, plan.va);
+    if plan.va < block_table_base(&MMU_CFG) || va_idx != idx {
+      trace::emit_panic("virtual address outside of the claimed block");
+      panic!(
+        "virtual address {:#x} outside of the claimed block {} (block index of the address {})",
+        plan.va, idx, va_idx
+      );
+    }
+  }
+
+  Ok(plan.va as *mut u8)
+}
+
+/// Determine the translation table entries ``maintain_pages`` would write to provide a virtual address for the given
+/// memory without changing the translation tables.
+///
+/// # Safety
+/// The result is only valid as long as the translation tables are not changed in between.
+/// # Errors
+/// ``MmuError::RequestTooLarge`` if the memory spans more level 2 blocks than a single mapping is allowed to use,
+/// ``MmuError::AlignmentUnsatisfiable`` if there is no free virtual address block with the requested alignment and
+/// ``MmuError::AddressSpaceExhausted`` if there is no free virtual address block left and
+/// ``MmuError::NoVirtualMapping`` if the setup of the translation tables is deferred to the first mapping
+pub(crate) unsafe fn plan_pages(
+  origin: *mut u8,
+  size: usize,
+  align: usize,
+) -> Result<MapPlan, MmuError> {
+  if BLOCK_TABLE.is_null() {
+    return Err(MmuError::NoVirtualMapping);
+  }
+  // the virtual address keeps the offset of the physical address into it's level 2 block, so the pages of a block
+  // that are not used by a mapping can be handed out to another mapping whose memory is located at the same offset
+  // within it's block
+  let granule = MMU_CFG.granule;
+  let block_shift = granule.block_shift();
+  let block_size = 1 << block_shift;
+  let block_mask = block_size - 1;
+  let page_shift = granule.page_shift();
+
+  // a bogus size shall not use up the whole virtual address range
+  let (pa, span) = page_span(origin as usize, size);
+  let offset = pa & block_mask;
+  let blocks = (offset.saturating_add(span).saturating_add(block_mask)) >> block_shift;
+  if blocks > MAX_BLOCKS_PER_MAP {
+    return Err(MmuError::RequestTooLarge);
+  }
+
+  // the offset into the block is the same as the one of the physical address, so an alignment smaller than a block
+  // need to be given by the physical address already, a larger one by the virtual address of the block
+  if origin as usize & (align.min(block_size) - 1) != 0 {
+    return Err(MmuError::AlignmentUnsatisfiable);
+  }
+  // the part of the n-th block of the mapping that is covered by the memory
+  let covered = |n: usize| {
+    let block_start = n << block_shift;
+    (
+      offset.max(block_start) - block_start,
+      (offset + span).min(block_start + block_size) - block_start,
+    )
+  };
+  let is_free = |idx: &usize| {
+    idx + blocks <= block_entries(&MMU_CFG)
+      && (0..blocks).all(|n| {
+        let (start, end) = covered(n);
+        pages_free(idx + n, start >> page_shift, end >> page_shift)
+      })
+  };
+  let is_aligned =
+    |idx: &usize| (block_table_base(&MMU_CFG) + (idx << block_shift)) & (align - 1) == 0;
+
+  // 1. find the next free blocks in the page table
+  let idx = (0..block_entries(&MMU_CFG))
+    .filter(is_aligned)
+    .find(is_free)
+    .ok_or_else(|| {
+      if (0..block_entries(&MMU_CFG)).any(|idx| is_free(&idx)) {
+        MmuError::AlignmentUnsatisfiable
+      } else {
+        let free_blocks = (0..block_entries(&MMU_CFG))
+          .filter(|&idx| !is_valid(read_entry(BLOCK_TABLE, idx)))
+          .count();
+        MmuError::address_space_exhausted(size, free_blocks)
+      }
+    })?;
+
+  // only the blocks completely covered by the memory are mapped as blocks, the others with the pages of a page table
+  let mut page_entries = 0;
+  let mut table_memory = 0;
+  for n in 0..blocks {
+    let (start, end) = covered(n);
+    let entry = read_entry(BLOCK_TABLE, idx + n);
+    if end - start < block_size {
+      page_entries += (end - start) >> page_shift;
+      if !is_table(2, entry) {
+        table_memory += granule.page_size();
+      }
+    }
+  }
+
+  // calculate the virtual address for this entry based on the current block we are using
+  let mut va = block_table_base(&MMU_CFG) + (idx << block_shift);
+  va |= origin as usize & block_mask;
+
+  Ok(MapPlan {
+    va,
+    block_entries: idx..idx + blocks,
+    page_entries,
+    table_memory,
+  })
+}
+
+/// The start address of the first page and the size of all pages covering the memory ``addr..addr + size``. At least
+/// one page is covered.
+fn page_span(addr: usize, size: usize) -> (usize, usize) {
+  let page_mask = unsafe { MMU_CFG.granule.page_size() } - 1;
+  let start = addr & !page_mask;
+  let end = addr.saturating_add(size.max(1)).saturating_add(page_mask) & !page_mask;
+  (start, end - start)
+}
+
+/// Whether the pages ``first..last`` of the block with the given index of the block table are not mapped. This is the
+/// case if the block is not used at all or the pages are invalid within it's page table.
+///
+/// # Safety
+/// The block table need to be set up
+unsafe fn pages_free(idx: usize, first: usize, last: usize) -> bool {
+  let entry = read_entry(BLOCK_TABLE, idx);
+  // the block table is a level 2 table
+  if is_table(2, entry) {
+    let table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *const u64;
+    (first..last).all(|page| !is_valid(read_entry(table, page)))
+  } else {
+    !is_valid(entry)
+  }
+}
+
+/// Remove the mapping of the pages covering the virtual address range ``va..va + size`` of the block table. The blocks
+/// and pages are invalidated and their cached translations are flushed, unless the flush is deferred. The page tables
+/// of the blocks no longer containing any mapping are released and the blocks are set to the sentinel mapping if it is
+/// configured. Returns the number of blocks released, which only counts the blocks that were in use before.
+///
+/// # Safety
+/// The memory of the range shall not be accessed any longer.
+unsafe fn clear_pages(va: usize, size: usize) -> usize {
+  let granule = MMU_CFG.granule;
+  let block_shift = granule.block_shift();
+  let block_size = 1 << block_shift;
+  let page_shift = granule.page_shift();
+  let (start, span) = page_span(va, size);
+  let first = va_to_block_index(&MMU_CFG, start);
+  let blocks = ((start & (block_size - 1)) + span + block_size - 1) >> block_shift;
+  let entries = first..(first + blocks).min(block_entries(&MMU_CFG));
+
+  // only the blocks that have been in use before are released, the unused ones of the range are not counted
+  let mut released = 0;
+  let mut tables = false;
+  for idx in entries.clone() {
+    let block_va = block_table_base(&MMU_CFG) + (idx << block_shift);
+    let from = start.max(block_va) - block_va;
+    let to = (start + span).min(block_va + block_size) - block_va;
+    let entry = read_entry(BLOCK_TABLE, idx);
+    if is_table(2, entry) {
+      let table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+      for page in from >> page_shift..to >> page_shift {
+        write_entry(table, page, 0);
+      }
+      aarch64_asm!("dsb   ishst");
+      tlb::invalidate_removed_range(block_va + from, to - from, 1 << page_shift);
+      tables = true;
+    } else if is_valid(entry) {
+      write_entry(BLOCK_TABLE, idx, 0);
+      aarch64_asm!("dsb   ishst");
+      tlb::invalidate_removed_va(block_va);
+      released += 1;
+    }
+  }
+
+  // the page tables and the sentinel mapping may only be written once the removed pages are no longer cached
+  if tables || SENTINEL_ENTRY != 0 {
+    tlb::flush_pending();
+  }
+  for idx in entries {
+    let entry = read_entry(BLOCK_TABLE, idx);
+    if is_table(2, entry) {
+      let table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+      if !is_table_tree_empty(granule, table, 3, granule.table_entries()) {
+        continue;
+      }
+      write_entry(BLOCK_TABLE, idx, 0);
+      aarch64_asm!("dsb   ishst");
+      // the walk of any address covered by the entry might still be cached
+      tlb::invalidate_va(block_table_base(&MMU_CFG) + (idx << block_shift));
+      release_table_tree(granule, table, 3, granule.table_entries());
+      released += 1;
+    } else if is_valid(entry) {
+      continue;
+    }
+    if SENTINEL_ENTRY != 0 {
+      write_entry(BLOCK_TABLE, idx, SENTINEL_ENTRY);
+      aarch64_asm!("dsb   ishst");
+    }
+  }
+
+  released
+}
+
+/// Map the physical memory range ``pa..pa + size`` to a new virtual address outside of the block table using level 1
+/// block entries for the 1GB aligned parts of the range and level 2 blocks or level 3 pages for the remainder. The
+/// virtual address keeps the offset of the physical address into it's 1GB block, so each 1GB aligned physical block
+/// is mapped with a single level 1 entry. The address range is carved from free consecutive root table entries.
+///
+/// # Safety
+/// The physical memory range need to be valid for the whole size given.
+/// # Errors
+/// ``MmuError::UnsupportedBlockSize`` if the granule does not support 1GB blocks or the table walk does not start at
+/// level 1, ``MmuError::SizeOverflow`` if the size exceeds the address range, ``MmuError::AddressSpaceExhausted`` if
+/// there are not enough free consecutive root table entries and ``MmuError::TableMemoryExhausted`` if there is no
+/// memory left for the next level tables of the remainder.
+pub(crate) unsafe fn map_huge(
+  pa: usize,
+  size: usize,
+  attributes: MemoryAttributes,
+) -> Result<*mut u8, MmuError> {
+  // the translation tables might be set up with the first mapping only
+  ensure_translation_tables();
+  let granule = MMU_CFG.granule;
+  if !granule.supports_block(1) || MMU_CFG.start_level() != 1 {
+    return Err(MmuError::UnsupportedBlockSize);
+  }
+  let gb_shift = granule.level_shift(1);
+  let gb_mask = (1 << gb_shift) - 1;
+  let page_mask = granule.page_size() - 1;
+
+  let span = ((pa & gb_mask)
+    .checked_add(size)
+    .and_then(|size| size.checked_add(gb_mask))
+    .ok_or(MmuError::SizeOverflow)?
+    >> gb_shift)
+    .max(1);
+  // the top most root table entry refers to the block table
+  let slots = MMU_CFG.root_entries() - 1;
+  let is_free = |idx: usize| !is_valid(read_entry(MMU_CFG.root, idx));
+  let idx = (0..(slots + 1).saturating_sub(span))
+    .find(|&idx| (idx..idx + span).all(is_free))
+    .ok_or_else(|| {
+      MmuError::address_space_exhausted(size, (0..slots).filter(|&idx| is_free(idx)).count())
+    })?;
+
+  let va = (!0 << MMU_CFG.va_bits) + (idx << gb_shift) + (pa & gb_mask);
+  let start = pa & !page_mask;
+  let end = pa
+    .checked_add(size)
+    .and_then(|end| end.checked_add(page_mask))
+    .ok_or(MmuError::SizeOverflow)?
+    & !page_mask;
+  map_range(
+    &MMU_CFG,
+    va & !page_mask,
+    start,
+    end - start,
+    attributes.raw(),
+  )
+  .ok_or(MmuError::TableMemoryExhausted)?;
+
+  stats::update(|stats| stats.maps += 1);
+  hook::notify(MapOp::Map, pa, va, size, attributes.raw());
+  Ok(va as *mut u8)
+}
+
+/// Release the virtual address blocks and pages a previous call to ``maintain_pages`` has provided the given virtual
+/// address and size from. The block and page entries are invalidated and their cached translations are flushed,
+/// unless the flush is deferred. A virtual address provided by ``map_huge`` is released with all block and page entries covering it.
+///
+/// # Safety
+/// The virtual address shall not be accessed any longer after it has been released.
+pub unsafe fn release_pages(va: *mut u8, size: usize) {
+  let first = va_to_block_index(&MMU_CFG, va as usize);
+  if first < block_entries(&MMU_CFG) {
+    let pa = translate(&MMU_CFG, va as usize).map_or(0, |(pa, _)| pa);
+    let released = clear_pages(va as usize, size);
+    stats::update(|stats| stats.unmaps += 1);
+    stats::claim_va_blocks(-(released as isize));
+    hook::notify(MapOp::Unmap, pa, va as usize, size, 0);
+  } else if !MMU_CFG.root.is_null() {
+    // the memory has been mapped with ``map_huge`` outside of the block table
+    let pa = translate(&MMU_CFG, va as usize).map_or(0, |(pa, _)| pa);
+    clear_range(&MMU_CFG, va as usize, size)
+      .unwrap_or_else(|| trace::fail("translation table memory exhausted"));
+    reclaim_root_entries(va as usize, size);
+    stats::update(|stats| stats.unmaps += 1);
+    hook::notify(MapOp::Unmap, pa, va as usize, size, 0);
+  }
+}
+
+/// Release the next level tables of the root table entries covering the virtual address range ``va..va + size`` once
+/// they no longer contain any mapping. The root table entry is invalidated and it's cached translations, as well as any
+/// deferred invalidation of removed mappings, are flushed before the tables are returned to the table memory pool. The
+/// top most entry referring to the block table is kept.
+unsafe fn reclaim_root_entries(va: usize, size: usize) {
+  let granule = MMU_CFG.granule;
+  let level = MMU_CFG.start_level();
+  let shift = granule.level_shift(level);
+  let entries = MMU_CFG.root_entries();
+  let first = (va >> shift) & (entries - 1);
+  let last = ((va + size.max(1) - 1) >> shift) & (entries - 1);
+
+  for idx in (first..=last).filter(|&idx| idx < entries - 1) {
+    let entry = read_entry(MMU_CFG.root, idx);
+    if !is_table(level, entry) {
+      continue;
+    }
+    let table = TTLB_TABLE::ADDR::from_raw(entry).raw_value() as *mut u64;
+    if is_table_tree_empty(granule, table, level + 1, granule.table_entries()) {
+      write_entry(MMU_CFG.root, idx, 0);
+      aarch64_asm!("dsb   ishst");
+      // the walk of any address covered by the entry might still be cached
+      tlb::invalidate_va((!0 << MMU_CFG.va_bits) + (idx << shift));
+      // the deferred invalidation of the removed pages may still refer to the tables, so it is done before they are
+      // handed out again
+      tlb::flush_pending();
+      release_table_tree(granule, table, level + 1, granule.table_entries());
+    }
+  }
+}
+
+/// The size in bytes of the largest range of consecutive free entries of the block table, which is the largest memory
+/// a single ``maintain_pages`` call is able to map. Returns ``0`` if the translation tables are not set up.
+///
+/// # Safety
+/// The result is only meaningful as long as the translation tables are not changed concurrently
+pub(crate) unsafe fn largest_free_range() -> usize {
+  if BLOCK_TABLE.is_null() {
+    return 0;
+  }
+  let mut largest = 0;
+  let mut run = 0;
+  for idx in 0..block_entries(&MMU_CFG) {
+    if is_valid(read_entry(BLOCK_TABLE, idx)) {
+      run = 0;
+    } else {
+      run += 1;
+      largest = largest.max(run);
+    }
+  }
+  largest << MMU_CFG.granule.block_shift()
+}
+
+/// The number of block entries available in the level 2 block table. If the table walk already starts at level 2 the
+/// block table is the root table.
+fn block_entries(config: &MmuConfig) -> usize {
+  if config.start_level() == 2 {
+    config.root_entries()
   } else {
-    // if there is no more virtual address block available we need to panic!
-    panic!("all VA addresses occupied");
+    config.granule.table_entries()
+  }
+}
+
+/// The index of the entry of the level 2 block table covering the given virtual address. The index is out of the range
+/// of the block table entries if the virtual address is not covered by the block table.
+fn va_to_block_index(config: &MmuConfig, va: usize) -> usize {
+  va.wrapping_sub(block_table_base(config)) >> config.granule.block_shift()
+}
+
+/// The virtual address the level 2 block table starts to cover. As it covers the top most part of the virtual
+/// address range this is the address range covered by the whole table below the end of the address range
+fn block_table_base(config: &MmuConfig) -> usize {
+  0usize.wrapping_sub(block_entries(config) << config.granule.block_shift())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tables::take_pool;
+
+  /// 6MB of physical memory starting at a 2MB boundary, spanning three level 2 blocks
+  const ORIGIN: usize = 0x1000_0000;
+  const SIZE: usize = 0x60_0000;
+
+  #[test]
+  fn multi_block_mapping_is_physically_contiguous() {
+    let _pool = take_pool();
+    unsafe {
+      setup_translation_tables(0, &MmuInitConfig::new(0x3000_0000, 0x0800_0000));
+      let attributes = MemoryAttributes::normal_cached().raw();
+      let va =
+        maintain_pages(ORIGIN as *mut u8, SIZE, attributes, 1).expect("memory mapped") as usize;
+
+      let page_size = MMU_CFG.granule.page_size();
+      let mut previous = None;
+      for offset in (0..SIZE).step_by(page_size) {
+        let pa = translate(&MMU_CFG, va + offset).map(|(pa, _)| pa);
+        assert_eq!(pa, Some(ORIGIN + offset));
+        assert!(previous < pa);
+        previous = pa;
+      }
+      reset();
+    }
+  }
+
+  #[test]
+  fn releasing_a_multi_block_mapping_frees_all_blocks() {
+    let _pool = take_pool();
+    unsafe {
+      setup_translation_tables(0, &MmuInitConfig::new(0x3000_0000, 0x0800_0000));
+      let attributes = MemoryAttributes::normal_cached().raw();
+      let va = maintain_pages(ORIGIN as *mut u8, SIZE, attributes, 1).expect("memory mapped");
+      let first = va_to_block_index(&MMU_CFG, va as usize);
+      assert_eq!(stats::stats().va_blocks_used, 3);
+      assert!((first..first + 3).all(|idx| is_valid(read_entry(BLOCK_TABLE, idx))));
+
+      release_pages(va, SIZE);
+      assert_eq!(stats::stats().va_blocks_used, 0);
+      assert!((first..first + 3).all(|idx| !is_valid(read_entry(BLOCK_TABLE, idx))));
+      assert_eq!(translate(&MMU_CFG, va as usize + SIZE - 1), None);
+      reset();
+    }
   }
 }
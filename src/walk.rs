@@ -0,0 +1,110 @@
+/***********************************************************************************************************************
+ * Copyright (c) 2020 by the authors
+ *
+ * Author: André Borrmann <pspwizard@gmx.de>
+ * License: Apache License 2.0 / MIT
+ **********************************************************************************************************************/
+
+//! # Translation Table Walk Attributes
+//!
+//! Decode the IRGNx, ORGNx and SHx fields of the active TCR_ELx register to tell how the hardware accesses the
+//! translation tables while walking them. The table walk, the MAIR slot of the table memory and the attributes the
+//! table memory is mapped with need to agree on the cacheability and shareability, otherwise the hardware might walk
+//! stale translation tables.
+//!
+
+use ruspiro_arch_aarch64::register::currentel;
+
+/// The cacheability of the memory accesses of the translation table walk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WalkCacheability {
+  /// Non-cacheable
+  NonCacheable,
+  /// Write-back, read-allocate, write-allocate cacheable
+  WriteBackWriteAllocate,
+  /// Write-through, read-allocate, no write-allocate cacheable
+  WriteThrough,
+  /// Write-back, read-allocate, no write-allocate cacheable
+  WriteBackNoWriteAllocate,
+}
+
+/// The shareability of the memory accesses of the translation table walk
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WalkShareability {
+  /// Non-shareable
+  NonShareable,
+  /// The reserved encoding 0b01
+  Reserved,
+  /// Outer shareable
+  OuterShareable,
+  /// Inner shareable
+  InnerShareable,
+}
+
+/// The attributes of the translation table walk of one address region
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RegionWalk {
+  /// The inner cacheability (IRGNx)
+  pub inner: WalkCacheability,
+  /// The outer cacheability (ORGNx)
+  pub outer: WalkCacheability,
+  /// The shareability (SHx)
+  pub shareability: WalkShareability,
+}
+
+/// The attributes of the translation table walks of the current exception level
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WalkAttributes {
+  /// The table walk of the lower (TTBR0) address region
+  pub ttbr0: RegionWalk,
+  /// The table walk of the upper (TTBR1) address region. This is only available in EL1.
+  pub ttbr1: Option<RegionWalk>,
+}
+
+/// Query the attributes of the translation table walks configured for the current exception level
+pub fn walk_attributes() -> WalkAttributes {
+  let el = currentel::read(currentel::EL::Field).value();
+  let tcr: u64;
+  unsafe {
+    if el == 2 {
+      aarch64_asm!("mrs $0, tcr_el2" : "=r"(tcr) ::: "volatile");
+    } else {
+      aarch64_asm!("mrs $0, tcr_el1" : "=r"(tcr) ::: "volatile");
+    }
+  }
+
+  WalkAttributes {
+    // IRGN0 [9:8], ORGN0 [11:10] and SH0 [13:12] are at the same position in TCR_EL1 and TCR_EL2
+    ttbr0: region_walk(tcr, 8),
+    // IRGN1 [25:24], ORGN1 [27:26] and SH1 [29:28] are only available in TCR_EL1
+    ttbr1: if el == 1 {
+      Some(region_walk(tcr, 24))
+    } else {
+      None
+    },
+  }
+}
+
+/// Decode the IRGNx, ORGNx and SHx fields starting at the given offset of the TCR_ELx register
+fn region_walk(tcr: u64, offset: u64) -> RegionWalk {
+  RegionWalk {
+    inner: cacheability(tcr >> offset),
+    outer: cacheability(tcr >> (offset + 2)),
+    shareability: match (tcr >> (offset + 4)) & 0b11 {
+      0b00 => WalkShareability::NonShareable,
+      0b01 => WalkShareability::Reserved,
+      0b10 => WalkShareability::OuterShareable,
+      _ => WalkShareability::InnerShareable,
+    },
+  }
+}
+
+/// Decode the cacheability given in the lower 2 bits of the value
+fn cacheability(value: u64) -> WalkCacheability {
+  match value & 0b11 {
+    0b00 => WalkCacheability::NonCacheable,
+    0b01 => WalkCacheability::WriteBackWriteAllocate,
+    0b10 => WalkCacheability::WriteThrough,
+    _ => WalkCacheability::WriteBackNoWriteAllocate,
+  }
+}